@@ -6,7 +6,7 @@ use std::ffi::CStr;
 use std::fs;
 use std::io;
 use std::mem::MaybeUninit;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use std::path::Path;
 use std::ptr;
 use std::str;
@@ -16,16 +16,32 @@ use serde::Deserialize;
 /// Configuration of a network interface.
 #[derive(Deserialize)]
 struct NetInterfaceConfig {
-    index: usize,
+    index: Option<u32>,
+    name: Option<String>,
     addr: Option<String>,
+    #[serde(default = "default_prefix_len")]
+    prefix_len: u8,
     gateway: Option<String>,
     broadcast: Option<String>,
+    /// Obtain `addr`/`prefix_len`/`gateway` from a DHCP lease instead of using the static values
+    /// above. `addr` must be left unset when this is set.
+    #[serde(default)]
+    dhcp: bool,
+}
+
+fn default_prefix_len() -> u8 {
+    24
 }
 
 /// Configuration of the network.
 #[derive(Deserialize)]
 struct NetConfig {
     interfaces: Vec<NetInterfaceConfig>,
+    /// DNS servers to write to `/etc/resolv.conf`.
+    #[serde(default)]
+    dns: Vec<String>,
+    /// Written, along with `localhost`, as a `/etc/hosts` entry for `127.0.0.1`.
+    hostname: String,
 }
 
 /// Configuration of the user interface that starts automatically on startup.
@@ -55,12 +71,29 @@ struct Mount {
     early: bool,
 }
 
+/// A `/proc/sys` knob to set at boot. `path` is either relative to `/proc/sys` (e.g.
+/// `vm/dirty_ratio`) or a dotted key (e.g. `vm.dirty_ratio`), both forms are accepted.
+#[derive(Deserialize)]
+struct Sysctl {
+    path: String,
+    value: String,
+}
+
 /// Build time configuration of the init system.
 #[derive(Deserialize)]
 struct Config {
     net: NetConfig,
     ui: UiConfig,
     mounts: Vec<Mount>,
+    sysctl: Vec<Sysctl>,
+    /// How long to wait for processes to exit on their own after broadcasting `SIGTERM` during
+    /// shutdown before giving up and broadcasting `SIGKILL`.
+    #[serde(default = "default_shutdown_grace_period_secs")]
+    shutdown_grace_period_secs: u64,
+}
+
+fn default_shutdown_grace_period_secs() -> u64 {
+    8
 }
 
 impl Config {
@@ -205,18 +238,61 @@ fn get_profile_env() -> HashMap<String, String> {
         .collect()
 }
 
+/// Emits a `Some(IpAddr::V4(...))`/`Some(IpAddr::V6(...))` literal (see `net::IpAddr`, imported as
+/// `IpAddr` into `config.rs`) for an address in `config.toml`, or `None` if there wasn't one.
 fn format_addr(s: Option<&str>) -> Cow<str> {
     s.map(|val| {
-        let addr: Ipv4Addr = val.parse().unwrap();
-        let octets = addr.octets();
-        Cow::Owned(format!(
-            "Some(u32::from_be_bytes([{}, {}, {}, {}]))",
-            octets[0], octets[1], octets[2], octets[3]
-        ))
+        let addr: IpAddr = val.parse().unwrap();
+        match addr {
+            IpAddr::V4(addr) => {
+                let o = addr.octets();
+                Cow::Owned(format!(
+                    "Some(IpAddr::V4([{}, {}, {}, {}]))",
+                    o[0], o[1], o[2], o[3]
+                ))
+            }
+            IpAddr::V6(addr) => Cow::Owned(format!("Some(IpAddr::V6({:?}))", addr.octets())),
+        }
     })
     .unwrap_or(Cow::Borrowed("None"))
 }
 
+/// Emits an `IpAddr::V4(...)`/`IpAddr::V6(...)` literal for a required (not `Option`) address,
+/// e.g. a DNS server.
+fn format_required_addr(s: &str) -> String {
+    let addr: IpAddr = s.parse().unwrap();
+    match addr {
+        IpAddr::V4(addr) => {
+            let o = addr.octets();
+            format!("IpAddr::V4([{}, {}, {}, {}])", o[0], o[1], o[2], o[3])
+        }
+        IpAddr::V6(addr) => format!("IpAddr::V6({:?})", addr.octets()),
+    }
+}
+
+/// Emits a `NetInterfaceId::Index(...)`/`NetInterfaceId::Name { ... }` literal from a
+/// `NetInterfaceConfig`'s `index`/`name` fields, exactly one of which must be set.
+fn format_interface_id(index: Option<u32>, name: Option<&str>) -> String {
+    match (index, name) {
+        (Some(index), None) => format!("NetInterfaceId::Index({index})"),
+        (None, Some(name)) => {
+            assert!(
+                name.len() <= 16,
+                "interface name {name:?} is longer than 16 bytes"
+            );
+            let mut bytes = [0u8; 16];
+            bytes[..name.len()].copy_from_slice(name.as_bytes());
+            format!(
+                "NetInterfaceId::Name {{ bytes: {:?}, len: {} }}",
+                bytes,
+                name.len()
+            )
+        }
+        (None, None) => panic!("network interface must have either an index or a name"),
+        (Some(_), Some(_)) => panic!("network interface cannot have both an index and a name"),
+    }
+}
+
 fn format_mount_function<'a, I: Iterator<Item = &'a Mount>>(fn_name: &str, mounts: I) -> String {
     let body = mounts
         .map(|m| {
@@ -243,6 +319,25 @@ fn format_mount_function<'a, I: Iterator<Item = &'a Mount>>(fn_name: &str, mount
     )
 }
 
+fn format_apply_sysctl<'a, I: Iterator<Item = &'a Sysctl>>(entries: I) -> String {
+    let body = entries
+        .map(|s| {
+            let path = s.path.replace('.', "/");
+            format!("        ret = linux::open(b\"/proc/sys/{path}\\0\" as *const u8, linux::O_WRONLY, 0);\n        if ret < 0 {{\n            return ret;\n        }}\n        let fd = ret as u32;\n        let n = linux::write(fd, b\"{value}\");\n        linux::close(fd);\n        if n < 0 {{\n            return n as i32;\n        }}\n", path = path, value = s.value)
+        })
+        .collect::<Vec<String>>()
+        .concat();
+    format!(
+        "pub fn apply_sysctl() -> i32 {{
+    #[allow(unused)]
+    let mut ret;
+    unsafe {{
+{body}    }}
+    0
+}}"
+    )
+}
+
 fn main() {
     let profile_env = get_profile_env();
     let system_path = profile_env.get("ROOTPATH").unwrap();
@@ -253,25 +348,42 @@ fn main() {
         .interfaces
         .iter()
         .map(|i| {
+            assert!(
+                !(i.dhcp && i.addr.is_some()),
+                "network interface cannot have both a static addr and dhcp set"
+            );
+            let id = format_interface_id(i.index, i.name.as_deref());
             let addr = format_addr(i.addr.as_deref());
             let gateway = format_addr(i.gateway.as_deref());
             let broadcast = format_addr(i.broadcast.as_deref());
             format!(
                 "    NetInterface {{
-        index: {index},
+        id: {id},
         addr: {addr},
+        prefix_len: {prefix_len},
         gateway: {gateway},
         broadcast: {broadcast},
+        dhcp: {dhcp},
     }},\n",
-                index = i.index,
+                id = id,
                 addr = addr,
+                prefix_len = i.prefix_len,
                 gateway = gateway,
-                broadcast = broadcast
+                broadcast = broadcast,
+                dhcp = i.dhcp
             )
         })
         .collect::<Vec<String>>()
         .concat();
 
+    let dns_servers_str = cfg
+        .net
+        .dns
+        .iter()
+        .map(|s| format_required_addr(s))
+        .collect::<Vec<String>>()
+        .join(", ");
+
     let passwd = Passwd::get_from_username(&cfg.ui.user);
     let user_groups = getgrouplist(&cfg.ui.user, passwd.gid);
     let user_groups_str = user_groups
@@ -319,6 +431,9 @@ pub const SYSTEM_PATH: *const u8 = b\"PATH={system_path}\\0\" as *const u8;
 pub const NET_INTERFACES: [NetInterface; {net_interfaces_len}] = [
 {net_interfaces_str}];
 
+pub const DNS_SERVERS: [IpAddr; {dns_servers_len}] = [{dns_servers_str}];
+pub const HOSTNAME: &str = {hostname:?};
+
 pub const USER_HOME: *const u8 = b\"{user_home}\\0\" as *const u8;
 pub const USER_UID: u32 = {user_uid};
 pub const USER_GID: u32 = {user_gid};
@@ -329,11 +444,17 @@ pub const SWAY_ENVP: *const *const u8 = &[
 
 pub const XDG_RUNTIME_DIR: *const u8 = b\"{xdg_runtime_dir}\\0\" as *const u8;
 
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs({shutdown_grace_period_secs});
+
 {mount_early}
 
 {mount_late}
+
+{apply_sysctl}
 ",
             net_interfaces_len = cfg.net.interfaces.len(),
+            dns_servers_len = cfg.net.dns.len(),
+            hostname = cfg.net.hostname,
             user_home = passwd.dir,
             user_uid = passwd.uid,
             user_gid = passwd.gid,
@@ -342,6 +463,8 @@ pub const XDG_RUNTIME_DIR: *const u8 = b\"{xdg_runtime_dir}\\0\" as *const u8;
                 format_mount_function("mount_early", cfg.mounts.iter().filter(|m| m.early)),
             mount_late =
                 format_mount_function("mount_late", cfg.mounts.iter().filter(|m| !m.early)),
+            apply_sysctl = format_apply_sysctl(cfg.sysctl.iter()),
+            shutdown_grace_period_secs = cfg.shutdown_grace_period_secs,
         ),
     )
     .unwrap();
@@ -18,3 +18,311 @@
 //! type of backup. Indeed, backups can either be full backups or incremental
 //! with a list of changes from a previous backup. This is made possible thanks
 //! to the btrfs filesystem which can calculate these diffs efficiently.
+//!
+//! Each day, a read-only snapshot of [`config::BACKUP_SOURCE`] is taken and sent (`btrfs send`)
+//! either in full, or incrementally against the most recent snapshot kept under
+//! [`config::BACKUP_SNAPSHOT_DIR`]. The send stream is compressed with `zstd` and then
+//! encrypted with `age` before being copied to every directory in [`config::BACKUP_REMOTES`].
+//! Old backups are then pruned: recent ones are all kept so that any of the last
+//! [`config::BACKUP_DAILY_RETENTION_DAYS`] days can be restored, older ones are thinned down to
+//! one full backup per [`config::BACKUP_FULL_ROTATION_DAYS`] so that a restore chain to some
+//! point in the past always exists, and nothing older than [`config::BACKUP_MAX_RETENTION_DAYS`]
+//! is kept at all.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use crate::config;
+
+/// One day, expressed as a duration, for readability when comparing backup ages.
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A backup file name, parsed back into its date and, for incremental backups, the date of the
+/// backup it is a diff from.
+#[derive(Debug, Clone)]
+struct BackupFile {
+    name: String,
+    date: String,
+    parent_date: Option<String>,
+}
+
+impl BackupFile {
+    fn parse(name: &str) -> Option<Self> {
+        let stem = name.strip_suffix(".zst.enc")?;
+        if let Some(date) = stem.strip_suffix("-full") {
+            return Some(Self {
+                name: name.to_owned(),
+                date: date.to_owned(),
+                parent_date: None,
+            });
+        }
+        let (date, parent_date) = stem.split_once("-from-")?;
+        Some(Self {
+            name: name.to_owned(),
+            date: date.to_owned(),
+            parent_date: Some(parent_date.to_owned()),
+        })
+    }
+
+    fn is_full(&self) -> bool {
+        self.parent_date.is_none()
+    }
+}
+
+/// Returns today's date as `<year>-<month>-<day>`.
+fn today() -> io::Result<String> {
+    let output = Command::new("/bin/date").arg("+%Y-%m-%d").output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "date exited with a non-zero status",
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Returns how many days ago `date` (`<year>-<month>-<day>`) was, relative to `today`, or `None`
+/// if either date cannot be parsed.
+fn days_since(date: &str, today: &str) -> Option<u64> {
+    fn to_day_number(date: &str) -> Option<i64> {
+        let mut parts = date.splitn(3, '-');
+        let year: i64 = parts.next()?.parse().ok()?;
+        let month: i64 = parts.next()?.parse().ok()?;
+        let day: i64 = parts.next()?.parse().ok()?;
+        // This is not calendar-accurate, but it is monotonic and good enough to order and
+        // bucket backups that are days to years apart.
+        Some(year * 372 + month * 31 + day)
+    }
+    let diff = to_day_number(today)? - to_day_number(date)?;
+    u64::try_from(diff).ok()
+}
+
+/// Lists the dates of the read-only snapshots already kept under
+/// [`config::BACKUP_SNAPSHOT_DIR`], most recent first.
+fn list_local_snapshots() -> io::Result<Vec<String>> {
+    let mut dates: Vec<String> = match fs::read_dir(config::BACKUP_SNAPSHOT_DIR) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .collect(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err),
+    };
+    dates.sort();
+    dates.reverse();
+    Ok(dates)
+}
+
+/// Takes a read-only snapshot of [`config::BACKUP_SOURCE`] under
+/// [`config::BACKUP_SNAPSHOT_DIR`], named after `date`.
+fn create_snapshot(date: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(config::BACKUP_SNAPSHOT_DIR)?;
+    let dest = Path::new(config::BACKUP_SNAPSHOT_DIR).join(date);
+    let status = Command::new("/sbin/btrfs")
+        .args(["subvolume", "snapshot", "-r"])
+        .arg(config::BACKUP_SOURCE)
+        .arg(&dest)
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "btrfs subvolume snapshot failed",
+        ));
+    }
+    Ok(dest)
+}
+
+/// Waits for `child`, turning a non-zero exit status into an `io::Error` naming `what`.
+fn wait_for(what: &str, mut child: Child) -> io::Result<()> {
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} exited with {}", what, status),
+        ));
+    }
+    Ok(())
+}
+
+/// Runs `btrfs send [-p <parent>] <snapshot>`, piping the stream through `zstd` and then `age`,
+/// and writes the result to `dest`.
+fn send_compress_encrypt(snapshot: &Path, parent: Option<&Path>, dest: &Path) -> io::Result<()> {
+    let mut send_cmd = Command::new("/sbin/btrfs");
+    send_cmd.arg("send");
+    if let Some(parent) = parent {
+        send_cmd.arg("-p").arg(parent);
+    }
+    let mut send = send_cmd.arg(snapshot).stdout(Stdio::piped()).spawn()?;
+
+    let mut zstd = Command::new("/usr/bin/zstd")
+        .args(["-q", "-T0"])
+        .stdin(send.stdout.take().unwrap())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut age = Command::new("/usr/bin/age")
+        .arg("-r")
+        .arg(config::BACKUP_AGE_RECIPIENT)
+        .stdin(zstd.stdout.take().unwrap())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut out_file = fs::File::create(dest)?;
+    io::copy(&mut age.stdout.take().unwrap(), &mut out_file)?;
+
+    wait_for("btrfs send", send)?;
+    wait_for("zstd", zstd)?;
+    wait_for("age", age)?;
+    Ok(())
+}
+
+/// Copies the staged backup file to every configured remote directory.
+fn mirror_to_remotes(staged: &Path, file_name: &str) {
+    for remote in config::BACKUP_REMOTES {
+        let dest = Path::new(remote).join(file_name);
+        // `fs::copy` takes the `copy_file_range`/`sendfile` fast path on Linux, so the backup
+        // file is copied by the kernel instead of round-tripping through userspace, which
+        // matters here because it can be large and the remote is often a different filesystem.
+        if let Err(err) = fs::copy(staged, &dest) {
+            eprintln!("failed to copy backup to {}: {:?}", remote, err);
+        }
+    }
+}
+
+/// Returns the date of the most recent full backup already shipped to `remote`, or `None` if
+/// there is none (e.g. a brand new remote).
+fn newest_full_date(remote: &str) -> io::Result<Option<String>> {
+    let mut dates: Vec<String> = match fs::read_dir(remote) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter_map(|name| BackupFile::parse(&name))
+            .filter(BackupFile::is_full)
+            .map(|f| f.date)
+            .collect(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err),
+    };
+    dates.sort();
+    Ok(dates.pop())
+}
+
+/// Decides which backups already on a remote are still worth keeping, given the retention
+/// policy described in the module documentation. `files` must be sorted most-recent-first.
+fn prune_plan<'a>(today: &str, files: &'a [BackupFile]) -> Vec<&'a BackupFile> {
+    let mut kept_full_period: Option<u64> = None;
+    files
+        .iter()
+        .filter(|f| {
+            let age = match days_since(&f.date, today) {
+                Some(age) => age,
+                // Keep anything we fail to parse the age of, to be safe.
+                None => return true,
+            };
+
+            if age > config::BACKUP_MAX_RETENTION_DAYS {
+                return false;
+            }
+            if age <= config::BACKUP_DAILY_RETENTION_DAYS {
+                return true;
+            }
+
+            // Beyond the daily window, only keep full backups, and only the first one we see
+            // (files are processed most-recent-first) in each rotation period, so a restore
+            // chain back to some point in every period always exists.
+            if !f.is_full() {
+                return false;
+            }
+            let period = age / config::BACKUP_FULL_ROTATION_DAYS;
+            if kept_full_period == Some(period) {
+                return false;
+            }
+            kept_full_period = Some(period);
+            true
+        })
+        .collect()
+}
+
+/// Applies the retention policy to a single remote directory.
+fn prune_remote(remote: &str, today: &str) -> io::Result<()> {
+    let mut files: Vec<BackupFile> = fs::read_dir(remote)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter_map(|name| BackupFile::parse(&name))
+        .collect();
+    // Most recent first, so `prune_plan` sees rotation periods in descending order.
+    files.sort_by(|a, b| b.date.cmp(&a.date));
+
+    let kept = prune_plan(today, &files);
+    for file in &files {
+        if kept.iter().any(|k| k.name == file.name) {
+            continue;
+        }
+        let path = Path::new(remote).join(&file.name);
+        if let Err(err) = fs::remove_file(&path) {
+            eprintln!("failed to prune old backup {}: {:?}", path.display(), err);
+        }
+    }
+    Ok(())
+}
+
+/// Takes a new backup, shipping it as a full backup rather than incremental whenever there is no
+/// local snapshot to diff against, or the most recent full on the primary remote is older than
+/// [`config::BACKUP_FULL_ROTATION_DAYS`], so that [`prune_plan`]'s "one full per rotation period"
+/// pruning always leaves a self-contained restore chain. Ships the result to every remote and
+/// prunes old backups there.
+fn run_backup() -> io::Result<()> {
+    let date = today()?;
+    let previous = list_local_snapshots()?;
+
+    let snapshot = create_snapshot(&date)?;
+
+    let primary_remote = *config::BACKUP_REMOTES
+        .first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no backup remotes configured"))?;
+    let full_is_due = match newest_full_date(primary_remote) {
+        Ok(Some(full_date)) => days_since(&full_date, &date)
+            .map_or(true, |age| age >= config::BACKUP_FULL_ROTATION_DAYS),
+        Ok(None) => true,
+        Err(err) => {
+            eprintln!(
+                "failed to check for an existing full backup on {}: {:?}",
+                primary_remote, err
+            );
+            false
+        }
+    };
+
+    let parent_date = if full_is_due { None } else { previous.first() };
+    let parent = parent_date.map(|d| Path::new(config::BACKUP_SNAPSHOT_DIR).join(d));
+    let file_name = match parent_date {
+        Some(parent_date) => format!("{date}-from-{parent_date}.zst.enc"),
+        None => format!("{date}-full.zst.enc"),
+    };
+    let staged = Path::new(config::BACKUP_SNAPSHOT_DIR).join(&file_name);
+    send_compress_encrypt(&snapshot, parent.as_deref(), &staged)?;
+    mirror_to_remotes(&staged, &file_name);
+    fs::remove_file(&staged)?;
+
+    for remote in config::BACKUP_REMOTES {
+        if let Err(err) = prune_remote(remote, &date) {
+            eprintln!("failed to prune old backups on {}: {:?}", remote, err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs [`run_backup`] once a day, forever. Meant to be run in its own thread.
+pub(crate) fn run_scheduled() {
+    loop {
+        if let Err(err) = run_backup() {
+            eprintln!("failed to take a backup: {:?}", err);
+        }
+        thread::sleep(DAY);
+    }
+}
@@ -0,0 +1,62 @@
+//! A small cgroup v2 subsystem used to track every process ginit starts (the UI, udevd, and
+//! anything they spawn in turn) so that none of them can escape being reaped at shutdown by
+//! forking away or ignoring `SIGTERM`, the way a bare `kill(-1, ...)` can.
+//!
+//! `cgroup.kill` and `cgroup.freeze` only exist on cgroup v2 and only on non-root cgroups, so
+//! [`create`] must run, and succeed, before any process is spawned and migrated into the cgroup
+//! with [`add_process`].
+
+use std::fs;
+use std::io;
+
+/// Where every process ginit starts ends up, so that they can all be tracked and killed
+/// atomically at shutdown.
+const CGROUP_DIR: &str = "/sys/fs/cgroup/ginit.service";
+
+fn write(name: &str, value: &str) -> io::Result<()> {
+    fs::write(format!("{}/{}", CGROUP_DIR, name), value)
+}
+
+/// Enables the `pids` controller on the root cgroup and creates the child cgroup that processes
+/// get migrated into. Must be called once, early, before any process is spawned.
+pub(crate) fn create() -> io::Result<()> {
+    fs::write("/sys/fs/cgroup/cgroup.subtree_control", "+pids")?;
+    fs::create_dir_all(CGROUP_DIR)
+}
+
+/// Migrates `pid` into the cgroup created by [`create`].
+pub(crate) fn add_process(pid: u32) -> io::Result<()> {
+    write("cgroup.procs", &pid.to_string())
+}
+
+/// Freezes every process in the cgroup, stopping them from running (but not killing them) until
+/// [`unfreeze`] is called.
+pub(crate) fn freeze() -> io::Result<()> {
+    write("cgroup.freeze", "1")
+}
+
+/// Resumes every process previously stopped by [`freeze`].
+pub(crate) fn unfreeze() -> io::Result<()> {
+    write("cgroup.freeze", "0")
+}
+
+/// Returns whether the cgroup's subtree still has any process left in it, by reading the
+/// `populated` field of `cgroup.events`.
+pub(crate) fn is_populated() -> io::Result<bool> {
+    let events = fs::read_to_string(format!("{}/cgroup.events", CGROUP_DIR))?;
+    for line in events.lines() {
+        if let Some(value) = line.strip_prefix("populated ") {
+            return Ok(value.trim() == "1");
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "cgroup.events has no populated field",
+    ))
+}
+
+/// Atomically sends `SIGKILL` to every process in the cgroup's subtree, including ones that
+/// forked away and would otherwise have escaped a plain `kill(-1, ...)`.
+pub(crate) fn kill() -> io::Result<()> {
+    write("cgroup.kill", "1")
+}
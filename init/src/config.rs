@@ -1,4 +1,5 @@
 use std::net::Ipv4Addr;
+use std::time::Duration;
 
 /// The index of the `lo` interface.
 pub(crate) const LO_INDEX: i32 = 1;
@@ -16,3 +17,35 @@ pub(crate) const USER_GROUPS: &'static [u32] = &[1000, 10, 18, 27, 97];
 /// This is what is set as the PATH environment variable.
 pub(crate) const EXEC_PATH: &'static str =
     "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin:/opt/bin:/usr/lib/llvm/12/bin";
+
+/// How long to wait for processes in the cgroup to exit on their own after being sent `SIGTERM`
+/// before giving up and writing to `cgroup.kill`.
+///
+/// Hardcoded rather than config.toml/build.rs-driven like the other settings in this file: this
+/// tree predates that generation pipeline (see `src/config.rs` for the crate that has it) and
+/// every other value here is a plain const too, so singling this one out for generation would be
+/// inconsistent with the rest of the file.
+pub(crate) const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(8);
+
+/// How many times to restart the UI process after it crashes before giving up and shutting down.
+pub(crate) const UI_MAX_RESTARTS: u32 = 3;
+
+/// The btrfs subvolume that gets snapshotted and backed up.
+pub(crate) const BACKUP_SOURCE: &'static str = "/bubble";
+/// Where read-only snapshots are kept on the local disk; the most recent one is the parent of
+/// the next incremental backup. Snapshots are never deleted from here.
+pub(crate) const BACKUP_SNAPSHOT_DIR: &'static str = "/bubble/.backup-snapshots";
+/// Directories (possibly on removable or network storage) that each receive a full copy of
+/// every backup file.
+pub(crate) const BACKUP_REMOTES: &'static [&'static str] =
+    &["/mnt/backup-usb", "/mnt/backup-nas"];
+/// `age` recipient used to encrypt backups; only the matching private key can decrypt them.
+pub(crate) const BACKUP_AGE_RECIPIENT: &'static str =
+    "age1qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqg9gr8z";
+/// Backups younger than this are kept daily, without any pruning.
+pub(crate) const BACKUP_DAILY_RETENTION_DAYS: u64 = 90;
+/// Backups are never kept for longer than this, even full ones.
+pub(crate) const BACKUP_MAX_RETENTION_DAYS: u64 = 2 * 365;
+/// Beyond the daily retention window, only the full backup closest to the start of each period
+/// of this many days is kept, so a restore chain to some point in the past always exists.
+pub(crate) const BACKUP_FULL_ROTATION_DAYS: u64 = 30;
@@ -0,0 +1,170 @@
+//! A declarative table of filesystems to mount at boot, instead of a hardcoded sequence of
+//! `mount()` calls, so the mount order is explicit and unmounting at shutdown can walk the same
+//! kind of entry in reverse.
+
+use std::ffi::CString;
+use std::fs::DirBuilder;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::ptr;
+
+use crate::libc_check_err;
+
+/// A single filesystem to mount, in the same shape `libc::mount` expects.
+pub(crate) struct Mount {
+    pub(crate) source: &'static str,
+    pub(crate) target: &'static str,
+    pub(crate) fstype: &'static str,
+    pub(crate) flags: libc::c_ulong,
+    pub(crate) data: Option<&'static str>,
+    /// If set, `target` is created with this mode before mounting.
+    pub(crate) mkdir_mode: Option<u32>,
+}
+
+const TMPFS_FLAGS: libc::c_ulong =
+    libc::MS_NOATIME | libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOSUID;
+
+/// Mounted during [`crate::mount_early`], before anything else runs.
+pub(crate) const EARLY: &[Mount] = &[
+    Mount {
+        source: "none",
+        target: "/dev",
+        fstype: "devtmpfs",
+        flags: libc::MS_NOATIME | libc::MS_NOEXEC | libc::MS_NOSUID,
+        data: None,
+        mkdir_mode: None,
+    },
+    Mount {
+        source: "none",
+        target: "/dev/shm",
+        fstype: "tmpfs",
+        flags: TMPFS_FLAGS,
+        data: None,
+        mkdir_mode: Some(0o1744),
+    },
+    Mount {
+        source: "none",
+        target: "/dev/pts",
+        fstype: "devpts",
+        flags: libc::MS_NOATIME | libc::MS_NOEXEC | libc::MS_NOSUID,
+        data: None,
+        mkdir_mode: Some(0o744),
+    },
+    Mount {
+        source: "none",
+        target: "/tmp",
+        fstype: "tmpfs",
+        flags: TMPFS_FLAGS,
+        data: None,
+        mkdir_mode: None,
+    },
+    Mount {
+        source: "none",
+        target: "/run",
+        fstype: "tmpfs",
+        flags: TMPFS_FLAGS,
+        data: None,
+        mkdir_mode: None,
+    },
+    Mount {
+        source: "none",
+        target: "/proc",
+        fstype: "proc",
+        flags: 0,
+        data: None,
+        mkdir_mode: None,
+    },
+    Mount {
+        source: "none",
+        target: "/sys",
+        fstype: "sysfs",
+        flags: 0,
+        data: None,
+        mkdir_mode: None,
+    },
+    Mount {
+        source: "none",
+        target: "/sys/fs/cgroup",
+        fstype: "cgroup2",
+        flags: libc::MS_NOATIME | libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOSUID,
+        data: None,
+        mkdir_mode: Some(0o755),
+    },
+    Mount {
+        source: "/dev/nvme0n1p2",
+        target: "/bubble",
+        fstype: "btrfs",
+        flags: libc::MS_NOATIME | libc::MS_NODEV,
+        data: Some("subvol=/@bubble,commit=900"),
+        mkdir_mode: None,
+    },
+];
+
+/// Mounted during [`crate::background_init`], once the rest of boot is underway.
+pub(crate) const LATE: &[Mount] = &[Mount {
+    source: "/dev/nvme0n1p1",
+    target: "/boot",
+    fstype: "vfat",
+    flags: libc::MS_NOATIME,
+    data: Some("umask=0077"),
+    mkdir_mode: None,
+}];
+
+fn mount_one(m: &Mount) -> io::Result<()> {
+    if let Some(mode) = m.mkdir_mode {
+        DirBuilder::new().mode(mode).create(m.target)?;
+    }
+    let source = CString::new(m.source).unwrap();
+    let target = CString::new(m.target).unwrap();
+    let fstype = CString::new(m.fstype).unwrap();
+    let data = m.data.map(|s| CString::new(s).unwrap());
+    libc_check_err(unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            m.flags,
+            data.as_ref()
+                .map(|s| s.as_ptr() as *const libc::c_void)
+                .unwrap_or(ptr::null()),
+        )
+    })
+    .map(|_ret| ())
+}
+
+/// Mounts every entry in `table`, in order. Stops at the first failure.
+pub(crate) fn mount_all(table: &[Mount]) -> io::Result<()> {
+    for m in table {
+        mount_one(m)?;
+    }
+    Ok(())
+}
+
+/// Unmounts `target`, escalating if it is still busy: first a lazy detach (`MNT_DETACH`), which
+/// lets it disappear from the namespace as soon as the last reference to it is dropped, then, as
+/// a last resort, a forced unmount (`MNT_FORCE`) so that shutdown is never blocked indefinitely by
+/// a wedged filesystem.
+pub(crate) fn unmount_with_escalation(target: &str) {
+    let target_cstr = CString::new(target).unwrap();
+    let err = match libc_check_err(unsafe { libc::umount(target_cstr.as_ptr()) }) {
+        Ok(_) => return,
+        Err(err) => err,
+    };
+    let busy = err.raw_os_error().map(|code| code == libc::EBUSY).unwrap_or(false);
+    if !busy {
+        eprintln!("failed to unmount {}: {:?}", target, err);
+        return;
+    }
+
+    eprintln!("{} is busy, lazily detaching it", target);
+    if let Err(err) =
+        libc_check_err(unsafe { libc::umount2(target_cstr.as_ptr(), libc::MNT_DETACH) })
+    {
+        eprintln!("failed to lazily detach {}: {:?}, forcing it", target, err);
+        if let Err(err) =
+            libc_check_err(unsafe { libc::umount2(target_cstr.as_ptr(), libc::MNT_FORCE) })
+        {
+            eprintln!("failed to forcibly unmount {}: {:?}", target, err);
+        }
+    }
+}
@@ -1,14 +1,42 @@
-use std::{ffi::CString, fs::File, io::Read, ptr};
+use std::{fs::File, io::Read, ptr, thread, time::Instant};
 
-use crate::libc_check_err;
+use crate::{cgroup, config, libc_check_err, mounts};
 
-/// Tell processes to exit and wait for them to do so. Errors are printed to
-/// stderr instead of being returned.
+/// Tell processes to exit and wait for them to do so, escalating to `cgroup.kill` if some are
+/// still around after [`config::SHUTDOWN_GRACE_PERIOD`]. Errors are printed to stderr instead of
+/// being returned.
 pub(crate) fn kill_all_processes() {
+    if let Err(err) = cgroup::freeze() {
+        eprintln!("failed to freeze cgroup: {:?}", err);
+    }
     if let Err(err) = libc_check_err(unsafe { libc::kill(-1, libc::SIGTERM) }) {
         eprintln!("failed to broadcast SIGTERM: {:?}", err);
     }
+    if let Err(err) = cgroup::unfreeze() {
+        eprintln!("failed to unfreeze cgroup: {:?}", err);
+    }
+
+    let deadline = Instant::now() + config::SHUTDOWN_GRACE_PERIOD;
+    loop {
+        match cgroup::is_populated() {
+            Ok(false) => break,
+            Ok(true) => {}
+            Err(err) => {
+                eprintln!("failed to read cgroup.events: {:?}", err);
+                break;
+            }
+        }
+        if Instant::now() >= deadline {
+            eprintln!("some processes did not exit in time, killing the cgroup");
+            if let Err(err) = cgroup::kill() {
+                eprintln!("failed to write cgroup.kill: {:?}", err);
+            }
+            break;
+        }
+        thread::sleep(std::time::Duration::from_millis(50));
+    }
 
+    // `cgroup.kill`/`SIGTERM` do not reap zombies for us; collect whatever is now dead.
     loop {
         if let Err(err) = libc_check_err(unsafe { libc::wait(ptr::null_mut()) }) {
             let no_child_left = err
@@ -23,8 +51,9 @@ pub(crate) fn kill_all_processes() {
     }
 }
 
-/// Tries to unmount all filesystem known to the init process. Errors are
-/// printed to stderr instead of being returned.
+/// Tries to unmount all filesystems known to the init process, escalating to a lazy (and, as a
+/// last resort, forced) detach for any that are still busy. Errors are printed to stderr instead
+/// of being returned.
 pub(crate) fn unmount_all() {
     let lines = {
         let mut file = match File::open("/proc/self/mounts") {
@@ -53,9 +82,6 @@ pub(crate) fn unmount_all() {
                 return;
             }
         };
-        let mountpoint_cstr = CString::new(mountpoint).unwrap();
-        if let Err(err) = libc_check_err(unsafe { libc::umount(mountpoint_cstr.as_ptr()) }) {
-            eprintln!("failed to unmount {}: {:?}", mountpoint, err);
-        }
+        mounts::unmount_with_escalation(mountpoint);
     }
 }
@@ -0,0 +1,152 @@
+//! Supervises the UI child process using a `pidfd` instead of comparing PIDs returned by a
+//! global `wait()`, which races with PID reuse once some other reaped child happens to get
+//! assigned the same number. Zombie reaping for every other child (udevd, backup helpers, ...)
+//! is left to [`reap_zombies_forever`], which runs on its own thread and never touches the UI
+//! child's exit status directly: [`peek_exit_was_crash`] peeks it with `WNOWAIT` so the zombie is
+//! still there, in its normal state, for the reaper thread to clean up afterwards.
+
+use std::io;
+use std::process::{Child, Command};
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::{config, libc_check_err};
+
+/// The pid of the UI child while [`run_ui`] is waiting on its pidfd and has not yet peeked its
+/// exit status, or `0` if there is none. Lets [`reap_zombies_forever`] leave that child's zombie
+/// alone instead of racing [`peek_exit_was_crash`]'s own `WNOWAIT` read of it.
+static UI_CHILD_PID: AtomicI32 = AtomicI32::new(0);
+
+fn pidfd_open(pid: u32) -> io::Result<libc::c_int> {
+    let ret = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    libc_check_err(ret as libc::c_int)
+}
+
+/// Blocks until `pidfd` becomes readable, which the kernel guarantees happens exactly when the
+/// associated process has exited.
+fn wait_for_readable(pidfd: libc::c_int) -> io::Result<()> {
+    let mut pollfd = libc::pollfd {
+        fd: pidfd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        match libc_check_err(unsafe { libc::poll(&mut pollfd, 1, -1) }) {
+            Ok(n) if n > 0 => return Ok(()),
+            Ok(_) => continue,
+            Err(err) if err.raw_os_error() == Some(libc::EINTR) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Reads the exit status of the process behind `pidfd` without reaping it, leaving that to
+/// [`reap_zombies_forever`]. Returns whether the process looks like it crashed, i.e. exited with
+/// a non-zero status or was killed by a signal.
+fn peek_exit_was_crash(pidfd: libc::c_int) -> io::Result<bool> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    libc_check_err(unsafe {
+        libc::waitid(
+            libc::P_PIDFD,
+            pidfd as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOWAIT,
+        )
+    })?;
+    Ok(unsafe { info.si_status() } != 0)
+}
+
+/// Continuously reaps any child process as soon as it exits, independently of the UI child's
+/// pidfd-based exit detection above. Meant to be run on its own thread for the entire lifetime of
+/// the program.
+///
+/// Peeks the next exited child with `WNOWAIT` before reaping it, so that a pid matching
+/// [`UI_CHILD_PID`] can be left alone for [`peek_exit_was_crash`] to read instead.
+pub(crate) fn reap_zombies_forever() {
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let ret = libc_check_err(unsafe {
+            libc::waitid(
+                libc::P_ALL,
+                0,
+                &mut info,
+                libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+            )
+        });
+        match ret {
+            Ok(_) => {
+                let pid = unsafe { info.si_pid() };
+                if pid == 0 || pid == UI_CHILD_PID.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(200));
+                } else if let Err(err) =
+                    libc_check_err(unsafe { libc::waitpid(pid, ptr::null_mut(), libc::WNOHANG) })
+                {
+                    eprintln!("waitpid failed: {:?}", err);
+                }
+            }
+            Err(err) => {
+                if err.raw_os_error() != Some(libc::ECHILD) {
+                    eprintln!("waitid failed: {:?}", err);
+                }
+                thread::sleep(Duration::from_millis(200));
+            }
+        }
+    }
+}
+
+/// Starts the UI process and waits for it to exit, restarting it up to
+/// [`config::UI_MAX_RESTARTS`] times if it looks like it crashed. Returns once the UI process has
+/// exited for good, either cleanly or because the restart budget ran out.
+pub(crate) fn run_ui(start: impl Fn() -> io::Result<Child>) {
+    let mut restarts_left = config::UI_MAX_RESTARTS;
+
+    loop {
+        let child = match start() {
+            Ok(val) => val,
+            Err(err) => {
+                eprintln!("failed to start UI process: {:?}", err);
+                return;
+            }
+        };
+        let pidfd = match pidfd_open(child.id()) {
+            Ok(val) => val,
+            Err(err) => {
+                eprintln!("failed to open pidfd for UI process: {:?}", err);
+                return;
+            }
+        };
+
+        UI_CHILD_PID.store(child.id() as libc::c_int, Ordering::SeqCst);
+        if let Err(err) = wait_for_readable(pidfd) {
+            eprintln!("failed to wait for UI process to exit: {:?}", err);
+            UI_CHILD_PID.store(0, Ordering::SeqCst);
+            unsafe { libc::close(pidfd) };
+            return;
+        }
+        let was_crash = peek_exit_was_crash(pidfd);
+        UI_CHILD_PID.store(0, Ordering::SeqCst);
+        unsafe { libc::close(pidfd) };
+        let was_crash = match was_crash {
+            Ok(val) => val,
+            Err(err) => {
+                eprintln!("failed to read UI process exit status: {:?}", err);
+                return;
+            }
+        };
+
+        if was_crash && restarts_left > 0 {
+            restarts_left -= 1;
+            eprintln!(
+                "UI process crashed, restarting it ({} attempts left)",
+                restarts_left
+            );
+            continue;
+        }
+
+        // Consider the system stopped once the UI process exits, whether it exited cleanly or
+        // ran out of restarts.
+        return;
+    }
+}
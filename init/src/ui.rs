@@ -6,7 +6,7 @@ use std::os::unix::process::CommandExt;
 use std::process::Child;
 use std::process::Command;
 
-use crate::{config, libc_check_err};
+use crate::{cgroup, config, libc_check_err};
 
 fn udev_trigger_add(ty: &str) -> io::Result<()> {
     let mut cmd = Command::new("/sbin/udevadm")
@@ -31,9 +31,12 @@ fn udev_trigger_add(ty: &str) -> io::Result<()> {
 
 pub(crate) fn start_ui() -> io::Result<Child> {
     // Configure all devices and wait for the end of the configuration.
-    Command::new("/sbin/udevd")
+    let udevd_child = Command::new("/sbin/udevd")
         .env("PATH", config::EXEC_PATH)
         .spawn()?;
+    if let Err(err) = cgroup::add_process(udevd_child.id()) {
+        eprintln!("failed to add udevd to cgroup: {:?}", err);
+    }
     if let Err(err) = udev_trigger_add("subsystems") {
         eprintln!("failed to add all subsystems to udev: {:?}", err);
     }
@@ -52,7 +55,7 @@ pub(crate) fn start_ui() -> io::Result<Child> {
         )
     })?;
 
-    Command::new("/usr/bin/sway")
+    let sway_child = Command::new("/usr/bin/sway")
         .uid(config::USER_UID)
         .gid(config::USER_GID)
         .groups(config::USER_GROUPS)
@@ -64,5 +67,9 @@ pub(crate) fn start_ui() -> io::Result<Child> {
         .env("WLR_SESSION", "direct")
         .env("XDG_RUNTIME_DIR", "/run/xdg-runtime-dir")
         .env("XDG_SEAT", "seat-main")
-        .spawn()
+        .spawn()?;
+    if let Err(err) = cgroup::add_process(sway_child.id()) {
+        eprintln!("failed to add sway to cgroup: {:?}", err);
+    }
+    Ok(sway_child)
 }
@@ -1,22 +1,37 @@
 //! Machine specific configuration is stored here. This means that it cannot be
 //! changed during runtime but has the benefit that we don't have to do any
 //! parsing at runtime which is easier and faster.
+//!
+//! Most of the content of this module is generated by `build.rs` from
+//! `config.toml` at build time; see that file for the constants and functions
+//! it emits.
 
-use std::net::Ipv4Addr;
+use std::time::Duration;
 
-/// The index of the `lo` interface.
-pub const LO_INDEX: i32 = 1;
-/// The index of the `eth0` interface.
-pub const ETH0_INDEX: i32 = 2;
-pub const ETH0_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 26);
-pub const ETH0_GATEWAY: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 254);
-pub const ETH0_BROADCAST: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+use crate::net::IpAddr;
 
-pub const USER_HOME: &'static str = "/home/greg";
-pub const USER_UID: u32 = 1000;
-pub const USER_GID: u32 = 1000;
-pub const USER_GROUPS: &'static [u32] = &[1000, 10, 18, 27, 78, 97, 272];
+/// How a [`NetInterface`] identifies the kernel interface it applies to.
+pub enum NetInterfaceId {
+    /// A kernel-assigned `ifindex`. Fragile: it can change across reboots or when hardware is
+    /// added/removed.
+    Index(u32),
+    /// An interface name (e.g. `eth0`), resolved to an index at runtime via
+    /// `NetlinkSocket::resolve_interface_index`.
+    Name { bytes: [u8; 16], len: u8 },
+}
 
-/// This is what is set as the PATH environment variable.
-pub const EXEC_PATH: &'static str =
-    "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin:/opt/bin:/usr/lib/llvm/12/bin";
+/// A network interface to bring up at boot.
+pub struct NetInterface {
+    pub id: NetInterfaceId,
+    pub addr: Option<IpAddr>,
+    /// The length, in bits, of the network prefix of `addr`.
+    pub prefix_len: u8,
+    pub gateway: Option<IpAddr>,
+    /// Only meaningful for an IPv4 `addr`; IPv6 has no concept of a broadcast address.
+    pub broadcast: Option<IpAddr>,
+    /// Whether to obtain an address, prefix length and gateway via DHCP instead of the static
+    /// `addr` above; `build.rs` rejects an interface that sets both.
+    pub dhcp: bool,
+}
+
+include!(concat!(env!("OUT_DIR"), "/config.rs"));
@@ -0,0 +1,415 @@
+//! A minimal DHCPv4 client for interfaces configured with `dhcp = true` in `config.toml`, which
+//! otherwise have no address. [`obtain_lease`] is called from `net::setup_networking`, after the
+//! interface has been brought admin-up, and runs the DISCOVER/OFFER/REQUEST/ACK handshake
+//! directly off a raw `AF_INET`/`SOCK_DGRAM` socket. This avoids embedding a full TCP/IP stack
+//! like smoltcp just for DHCP, at the cost of driving the handshake ourselves.
+
+use core::convert::{TryFrom, TryInto};
+use core::{mem, ptr, slice};
+
+use crate::linux;
+use crate::net;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHER: u8 = 1;
+const MAGIC_COOKIE: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// Not necessarily in every vendored `libc` version, but the value is stable across kernels.
+const SO_BINDTOIFINDEX: i32 = 62;
+
+/// How long to wait for a response to a DISCOVER or REQUEST before giving up.
+const RESPONSE_TIMEOUT_MS: i32 = 5_000;
+
+/// The lease obtained for an interface by [`obtain_lease`].
+pub struct Lease {
+    pub addr: [u8; 4],
+    pub prefix_len: u8,
+    pub router: Option<[u8; 4]>,
+}
+
+/// Mirrors the kernel's `struct sockaddr_in`, the address type passed to `bind()`/`sendto()` for
+/// an `AF_INET` socket. `sin_addr` is kept as raw octets (like `net::IpAddr`), since it is already
+/// in network byte order; only `sin_port`, a host-native value here, needs converting.
+#[repr(C)]
+struct sockaddr_in {
+    sin_family: u16,
+    sin_port: u16,
+    sin_addr: [u8; 4],
+    sin_zero: [u8; 8],
+}
+
+impl sockaddr_in {
+    fn new(port: u16, addr: [u8; 4]) -> Self {
+        sockaddr_in {
+            sin_family: u16::try_from(linux::AF_INET).unwrap(),
+            sin_port: port.to_be(),
+            sin_addr: addr,
+            sin_zero: [0; 8],
+        }
+    }
+}
+
+/// The fixed-size part of a BOOTP/DHCP packet (RFC 2131 section 2); the variable-length
+/// `options` follow it. Multi-byte fields are kept as raw, already-network-order octets (like
+/// `net::IpAddr`) rather than as integers, so no endian conversion is needed to build or parse a
+/// packet.
+#[repr(C)]
+struct Header {
+    op: u8,
+    htype: u8,
+    hlen: u8,
+    hops: u8,
+    xid: [u8; 4],
+    secs: [u8; 2],
+    flags: [u8; 2],
+    ciaddr: [u8; 4],
+    yiaddr: [u8; 4],
+    siaddr: [u8; 4],
+    giaddr: [u8; 4],
+    chaddr: [u8; 16],
+    sname: [u8; 64],
+    file: [u8; 128],
+    magic_cookie: [u8; 4],
+}
+
+/// A broadcast-enabled UDP socket bound to the wildcard address on the DHCP client port and tied
+/// to `interface_index` with `SO_BINDTOIFINDEX`, for exchanging BOOTP/DHCP packets with the
+/// network's DHCP server.
+struct DhcpSocket {
+    fd: u32,
+    epfd: linux::Fd,
+}
+
+impl DhcpSocket {
+    fn new(interface_index: u32) -> Result<DhcpSocket, i32> {
+        let fd = linux::socket(linux::AF_INET, linux::SOCK_DGRAM, 0);
+        if fd < 0 {
+            return Err(fd);
+        }
+        let fd = u32::try_from(fd).unwrap();
+
+        let epfd = linux::epoll_create1(linux::EPOLL_CLOEXEC);
+        if epfd < 0 {
+            linux::close(fd);
+            return Err(epfd);
+        }
+        let epfd = linux::Fd(epfd.try_into().unwrap());
+        let mut event = linux::epoll_event {
+            events: linux::EPOLLIN,
+            data: fd.into(),
+        };
+        let ret =
+            unsafe { linux::epoll_ctl(epfd.0, linux::EPOLL_CTL_ADD, fd, &mut event as *mut _) };
+        if ret < 0 {
+            linux::close(fd);
+            return Err(ret);
+        }
+
+        // From here on, `fd` is owned by `socket` and gets closed by its `Drop` impl even if a
+        // later setup step below fails.
+        let socket = DhcpSocket { fd, epfd };
+
+        let broadcast: i32 = 1;
+        let ret = unsafe {
+            linux::setsockopt(
+                socket.fd,
+                libc::SOL_SOCKET,
+                libc::SO_BROADCAST,
+                &broadcast as *const i32 as *const u8,
+                u32::try_from(mem::size_of::<i32>()).unwrap(),
+            )
+        };
+        if ret < 0 {
+            return Err(ret);
+        }
+
+        let ret = unsafe {
+            linux::setsockopt(
+                socket.fd,
+                libc::SOL_SOCKET,
+                SO_BINDTOIFINDEX,
+                &interface_index as *const u32 as *const u8,
+                u32::try_from(mem::size_of::<u32>()).unwrap(),
+            )
+        };
+        if ret < 0 {
+            return Err(ret);
+        }
+
+        let addr = sockaddr_in::new(DHCP_CLIENT_PORT, [0, 0, 0, 0]);
+        let ret = unsafe {
+            linux::bind(
+                socket.fd,
+                &addr as *const sockaddr_in as *const u8,
+                u32::try_from(mem::size_of::<sockaddr_in>()).unwrap(),
+            )
+        };
+        if ret < 0 {
+            return Err(ret);
+        }
+
+        Ok(socket)
+    }
+
+    /// Broadcasts `packet` to the DHCP server port.
+    fn send(&self, packet: &[u8]) -> i64 {
+        let dest = sockaddr_in::new(DHCP_SERVER_PORT, [255, 255, 255, 255]);
+        unsafe {
+            linux::sendto(
+                self.fd,
+                packet.as_ptr(),
+                packet.len(),
+                0,
+                &dest as *const sockaddr_in as *const u8,
+                u32::try_from(mem::size_of::<sockaddr_in>()).unwrap(),
+            )
+        }
+    }
+
+    /// Waits until `deadline_ms` (an absolute [`net::monotonic_ms`] value) for a datagram and
+    /// reads it into `buf`, returning the number of bytes read, or `-libc::ETIMEDOUT` if the
+    /// deadline passes first.
+    fn recv(&self, buf: &mut [u8], deadline_ms: i64) -> Result<usize, i32> {
+        let remaining = deadline_ms - net::monotonic_ms();
+        if remaining <= 0 {
+            return Err(-libc::ETIMEDOUT);
+        }
+        let mut events = [linux::epoll_event { events: 0, data: 0 }; 1];
+        let n = linux::epoll_wait(self.epfd.0, &mut events, i32::try_from(remaining).unwrap());
+        if n < 0 {
+            return Err(n);
+        }
+        if n == 0 {
+            return Err(-libc::ETIMEDOUT);
+        }
+        let len = unsafe {
+            linux::recvfrom(
+                self.fd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if len < 0 {
+            return Err(len.try_into().unwrap());
+        }
+        Ok(usize::try_from(len).unwrap())
+    }
+}
+
+impl Drop for DhcpSocket {
+    fn drop(&mut self) {
+        if linux::close(self.fd) < 0 {
+            // TODO: Print an error.
+        }
+    }
+}
+
+/// Appends a TLV DHCP option (`code`, a 1-byte length, then `val`) to `buf` at `*len`.
+fn push_option(buf: &mut [u8], len: &mut usize, code: u8, val: &[u8]) {
+    buf[*len] = code;
+    buf[*len + 1] = u8::try_from(val.len()).unwrap();
+    buf[*len + 2..*len + 2 + val.len()].copy_from_slice(val);
+    *len += 2 + val.len();
+}
+
+/// Builds a `message_type` DHCP packet with `xid`/`chaddr` and `extra_options` (written after the
+/// mandatory message-type option, before the terminating `OPT_END`) into `buf`, returning the
+/// number of bytes written.
+fn build_packet(
+    buf: &mut [u8],
+    xid: [u8; 4],
+    chaddr: [u8; 6],
+    message_type: u8,
+    extra_options: &[(u8, &[u8])],
+) -> usize {
+    let mut padded_chaddr = [0u8; 16];
+    padded_chaddr[..6].copy_from_slice(&chaddr);
+    let header = Header {
+        op: BOOTREQUEST,
+        htype: HTYPE_ETHER,
+        hlen: 6,
+        hops: 0,
+        xid,
+        secs: [0, 0],
+        flags: [0, 0],
+        ciaddr: [0; 4],
+        yiaddr: [0; 4],
+        siaddr: [0; 4],
+        giaddr: [0; 4],
+        chaddr: padded_chaddr,
+        sname: [0; 64],
+        file: [0; 128],
+        magic_cookie: MAGIC_COOKIE,
+    };
+    let header_bytes = unsafe {
+        slice::from_raw_parts(
+            (&header as *const Header) as *const u8,
+            mem::size_of::<Header>(),
+        )
+    };
+    let mut len = header_bytes.len();
+    buf[..len].copy_from_slice(header_bytes);
+
+    push_option(buf, &mut len, OPT_MESSAGE_TYPE, &[message_type]);
+    for &(code, val) in extra_options {
+        push_option(buf, &mut len, code, val);
+    }
+    buf[len] = OPT_END;
+    len += 1;
+    len
+}
+
+/// Walks the DHCP options trailing a `Header`, calling `f(code, value)` for each one, skipping
+/// the zero-length pad option and stopping at `OPT_END` or once `options` is exhausted.
+fn for_each_option(options: &[u8], mut f: impl FnMut(u8, &[u8])) {
+    let mut i = 0;
+    while i < options.len() {
+        let code = options[i];
+        if code == OPT_END {
+            return;
+        }
+        if code == 0 {
+            i += 1;
+            continue;
+        }
+        if i + 1 >= options.len() {
+            return;
+        }
+        let opt_len = usize::from(options[i + 1]);
+        let val_start = i + 2;
+        if val_start + opt_len > options.len() {
+            return;
+        }
+        f(code, &options[val_start..val_start + opt_len]);
+        i = val_start + opt_len;
+    }
+}
+
+/// The fields of a DHCP reply that the handshake in [`obtain_lease`] cares about.
+struct Reply {
+    message_type: u8,
+    yiaddr: [u8; 4],
+    router: Option<[u8; 4]>,
+    server_id: Option<[u8; 4]>,
+    subnet_mask: Option<[u8; 4]>,
+}
+
+/// Parses `buf` as a `BOOTREPLY` DHCP packet matching `xid`, or `None` if it isn't one.
+fn parse_reply(buf: &[u8], xid: [u8; 4]) -> Option<Reply> {
+    if buf.len() < mem::size_of::<Header>() {
+        return None;
+    }
+    let header = unsafe { ptr::read_unaligned(buf.as_ptr() as *const Header) };
+    if header.op != BOOTREPLY || header.xid != xid || header.magic_cookie != MAGIC_COOKIE {
+        return None;
+    }
+
+    let mut message_type = None;
+    let mut router = None;
+    let mut server_id = None;
+    let mut subnet_mask = None;
+    for_each_option(&buf[mem::size_of::<Header>()..], |code, val| {
+        match (code, val.len()) {
+            (OPT_MESSAGE_TYPE, 1) => message_type = Some(val[0]),
+            (OPT_ROUTER, 4) => router = Some(val.try_into().unwrap()),
+            (OPT_SERVER_ID, 4) => server_id = Some(val.try_into().unwrap()),
+            (OPT_SUBNET_MASK, 4) => subnet_mask = Some(val.try_into().unwrap()),
+            _ => {}
+        }
+    });
+
+    Some(Reply {
+        message_type: message_type?,
+        yiaddr: header.yiaddr,
+        router,
+        server_id,
+        subnet_mask,
+    })
+}
+
+/// Runs the DISCOVER/OFFER/REQUEST/ACK handshake on `interface_index` and returns the lease
+/// obtained.
+pub fn obtain_lease(interface_index: u32) -> Result<Lease, i32> {
+    let chaddr = net::get_interface_mac(interface_index)?;
+    let socket = DhcpSocket::new(interface_index)?;
+
+    let mut xid = [0u8; 4];
+    if linux::getrandom(&mut xid) < 0 {
+        return Err(-libc::EIO);
+    }
+
+    let mut buf = [0u8; 576];
+    let len = build_packet(&mut buf, xid, chaddr, DHCPDISCOVER, &[]);
+    let ret = socket.send(&buf[..len]);
+    if ret < 0 {
+        return Err(ret.try_into().unwrap());
+    }
+    let deadline = net::monotonic_ms() + i64::from(RESPONSE_TIMEOUT_MS);
+    let offer = loop {
+        let mut reply = [0u8; 576];
+        let reply_len = socket.recv(&mut reply, deadline)?;
+        if let Some(parsed) = parse_reply(&reply[..reply_len], xid) {
+            if parsed.message_type == DHCPOFFER {
+                break parsed;
+            }
+        }
+    };
+    let server_id = offer.server_id.ok_or(-libc::EPROTO)?;
+
+    let len = build_packet(
+        &mut buf,
+        xid,
+        chaddr,
+        DHCPREQUEST,
+        &[
+            (OPT_REQUESTED_IP, &offer.yiaddr[..]),
+            (OPT_SERVER_ID, &server_id[..]),
+        ],
+    );
+    let ret = socket.send(&buf[..len]);
+    if ret < 0 {
+        return Err(ret.try_into().unwrap());
+    }
+    let deadline = net::monotonic_ms() + i64::from(RESPONSE_TIMEOUT_MS);
+    let ack = loop {
+        let mut reply = [0u8; 576];
+        let reply_len = socket.recv(&mut reply, deadline)?;
+        if let Some(parsed) = parse_reply(&reply[..reply_len], xid) {
+            match parsed.message_type {
+                DHCPACK => break parsed,
+                DHCPNAK => return Err(-libc::ECONNREFUSED),
+                _ => {}
+            }
+        }
+    };
+
+    let prefix_len = ack
+        .subnet_mask
+        .map(|mask| u8::try_from(u32::from_be_bytes(mask).count_ones()).unwrap())
+        .unwrap_or(32);
+    Ok(Lease {
+        addr: ack.yiaddr,
+        prefix_len,
+        router: ack.router,
+    })
+}
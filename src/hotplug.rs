@@ -0,0 +1,116 @@
+//! `add_dri_render_permissions` used to `chown` `/dev/dri/renderD128` once, unconditionally, at a
+//! fixed point during boot: if udevd had not created the node yet, the `chown` silently failed,
+//! and any node that appeared later (hot-plugged, or simply created late by udevd) never got the
+//! right ownership. This module watches the directories device nodes appear in with `inotify`
+//! instead, and applies the configured ownership to every node as it is created, whether that
+//! happens during udevd's initial coldplug or from a later hotplug event.
+
+use core::convert::{TryFrom, TryInto};
+use core::fmt::Write;
+use core::mem;
+
+use crate::config;
+use crate::linux;
+
+/// Directories to watch for newly created device nodes. Everything created in any of them gets
+/// the same ownership, [`config::USER_UID`]/[`config::USER_GID`].
+const WATCHED_DIRS: &[&[u8]] = &[b"/dev/dri\0", b"/dev/input\0"];
+
+#[derive(Copy, Clone)]
+struct Watch {
+    wd: i32,
+    dir: &'static [u8],
+}
+
+/// Watches [`WATCHED_DIRS`] for newly created device nodes and `chown`s them to
+/// [`config::USER_UID`]/[`config::USER_GID`] as they appear.
+pub struct HotplugWatcher {
+    fd: linux::Fd,
+    watches: [Watch; WATCHED_DIRS.len()],
+}
+
+impl HotplugWatcher {
+    pub fn new() -> Result<Self, i32> {
+        let fd = linux::inotify_init1(linux::IN_NONBLOCK | linux::IN_CLOEXEC);
+        if fd < 0 {
+            return Err(fd);
+        }
+        let fd = linux::Fd(fd.try_into().unwrap());
+
+        let mut watches = [Watch { wd: -1, dir: b"" }; WATCHED_DIRS.len()];
+        for (i, dir) in WATCHED_DIRS.iter().enumerate() {
+            // The NUL terminator was included in the literal, so this is a valid C string.
+            let wd = unsafe { linux::inotify_add_watch(fd.0, dir.as_ptr(), linux::IN_CREATE) };
+            if wd < 0 {
+                return Err(wd);
+            }
+            watches[i] = Watch { wd, dir };
+        }
+
+        Ok(Self { fd, watches })
+    }
+
+    pub fn fd(&self) -> u32 {
+        self.fd.0
+    }
+
+    /// Drains and handles every pending inotify event, `chown`ing any newly created node to the
+    /// configured user.
+    pub fn process_events(&self) {
+        loop {
+            let mut buf = [0u8; 256];
+            let n = linux::read(self.fd.0, &mut buf);
+            if n == -i64::from(linux::EAGAIN) {
+                return;
+            } else if n < 0 {
+                writeln!(linux::Stderr, "failed to read inotify events: {n}").unwrap();
+                return;
+            }
+            let n = usize::try_from(n).unwrap();
+
+            let header_size = mem::size_of::<linux::inotify_event>();
+            let mut offset = 0;
+            while offset + header_size <= n {
+                // SAFETY: the kernel always writes a full `struct inotify_event` header before
+                // any variable-length name, and we just checked that it fits in what was read.
+                let event =
+                    unsafe { &*(buf[offset..].as_ptr() as *const linux::inotify_event) };
+                let name_start = offset + header_size;
+                let name_end = name_start + event.len as usize;
+                if name_end > n {
+                    break;
+                }
+                let name = &buf[name_start..name_end];
+                let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                self.handle_event(event.wd, &name[..name_len]);
+                offset = name_end;
+            }
+        }
+    }
+
+    fn handle_event(&self, wd: i32, name: &[u8]) {
+        let dir = match self.watches.iter().find(|w| w.wd == wd) {
+            Some(watch) => watch.dir,
+            None => return,
+        };
+
+        // `dir` includes the trailing NUL; replace it with `/` and append `name\0`.
+        let mut path = [0u8; 64];
+        let dir_without_nul = &dir[..dir.len() - 1];
+        let total_len = dir_without_nul.len() + 1 + name.len() + 1;
+        if total_len > path.len() {
+            return;
+        }
+        let mut cursor = 0;
+        path[cursor..cursor + dir_without_nul.len()].copy_from_slice(dir_without_nul);
+        cursor += dir_without_nul.len();
+        path[cursor] = b'/';
+        cursor += 1;
+        path[cursor..cursor + name.len()].copy_from_slice(name);
+
+        let ret = unsafe { linux::chown(path.as_ptr(), config::USER_UID, config::USER_GID) };
+        if ret < 0 {
+            writeln!(linux::Stderr, "failed to chown new device node: {ret}").unwrap();
+        }
+    }
+}
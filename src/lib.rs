@@ -2,7 +2,6 @@
 
 pub mod config;
 pub mod shutdown;
-pub mod sysctl;
 pub mod ui;
 
 use std::{ffi::CString, io, ptr};
@@ -1,6 +1,6 @@
 use core::arch::asm;
 use core::fmt::Write;
-use core::{fmt, ptr};
+use core::{fmt, mem, ptr};
 
 pub const AF_UNSPEC: i32 = 0;
 pub const AF_INET: i32 = 2;
@@ -9,10 +9,25 @@ pub const AF_NETLINK: i32 = 16;
 pub const ARPHRD_NONE: u16 = 0xFFFE;
 
 pub const CLONE_VM: u64 = 0x100;
+pub const CLONE_PIDFD: u64 = 0x1000;
 pub const CLONE_VFORK: u64 = 0x4000;
 
+pub const EPOLL_CLOEXEC: i32 = 0x80000;
+
+pub const EPOLL_CTL_ADD: i32 = 1;
+pub const EPOLL_CTL_DEL: i32 = 2;
+pub const EPOLL_CTL_MOD: i32 = 3;
+
+pub const EPOLLIN: u32 = 0x001;
+pub const EPOLLERR: u32 = 0x008;
+pub const EPOLLHUP: u32 = 0x010;
+/// Requests edge-triggered notification instead of the default level-triggered one.
+pub const EPOLLET: u32 = 1 << 31;
+
 pub const ESRCH: i32 = 3;
 pub const EINTR: i32 = 4;
+pub const EACCES: i32 = 13;
+pub const EAGAIN: i32 = 11;
 pub const ECHILD: i32 = 10;
 pub const ENOMEM: i32 = 12;
 pub const EINVAL: i32 = 22;
@@ -23,6 +38,11 @@ pub const IFA_BROADCAST: u16 = 4;
 
 pub const IFF_UP: i32 = 0x1;
 
+/// A file was created in a watched directory.
+pub const IN_CREATE: u32 = 0x100;
+pub const IN_NONBLOCK: i32 = 0o4000;
+pub const IN_CLOEXEC: i32 = 0o2000000;
+
 pub const LINUX_REBOOT_MAGIC1: i32 = 0xfee1deadu32 as i32;
 pub const LINUX_REBOOT_MAGIC2: i32 = 672274793;
 
@@ -42,8 +62,13 @@ pub const NLM_F_CREATE: i32 = 0x400;
 
 pub const O_RDONLY: u32 = 0o0;
 pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
 pub const O_CREAT: u32 = 0o100;
+pub const O_NOCTTY: u32 = 0o400;
 pub const O_TRUNC: u32 = 0o1000;
+pub const O_NONBLOCK: u32 = 0o4000;
+pub const O_NOFOLLOW: u32 = 0o400000;
+pub const O_CLOEXEC: u32 = 0o2000000;
 
 pub const RB_POWER_OFF: u32 = 0x4321FEDC;
 
@@ -62,11 +87,106 @@ pub const RT_SCOPE_UNIVERSE: u8 = 0;
 
 pub const RT_TABLE_MAIN: u8 = 254;
 
+pub const SFD_NONBLOCK: i32 = 0o4000;
+pub const SFD_CLOEXEC: i32 = 0o2000000;
+
 pub const SIGTERM: i32 = 15;
+pub const SIGUSR1: i32 = 10;
+pub const SIGUSR2: i32 = 12;
 pub const SIGCHLD: i32 = 17;
 
+/// Block the listed signals (used with [`rt_sigprocmask`]).
+pub const SIG_BLOCK: i32 = 0;
+
+pub const SOCK_DGRAM: i32 = 2;
 pub const SOCK_RAW: i32 = 3;
 
+pub const SOL_SOCKET: i32 = 1;
+pub const SO_BROADCAST: i32 = 6;
+
+pub const WNOHANG: i32 = 1;
+
+/// Puts the tty in process mode (see [`vt_mode`]): VT switches are only performed after the
+/// controlling process acknowledges them with `VT_RELDISP`, instead of happening immediately.
+pub const VT_SETMODE: u64 = 0x5602;
+/// Acknowledges a VT switch. Write `1` to allow a release request, or [`VT_ACKACQ`] once the VT
+/// has been reacquired.
+pub const VT_RELDISP: u64 = 0x5605;
+/// [`vt_mode::mode`] value requesting process mode.
+pub const VT_PROCESS: i8 = 1;
+/// Value to write to [`VT_RELDISP`] to acknowledge having reacquired the VT.
+pub const VT_ACKACQ: u64 = 2;
+
+/// Acquires DRM master on the fd again, once this VT is the active one.
+pub const DRM_IOCTL_SET_MASTER: u64 = 0x641e;
+/// Drops DRM master on the fd, so another process (or nobody) can hold it while this VT is not
+/// the active one.
+pub const DRM_IOCTL_DROP_MASTER: u64 = 0x641f;
+/// Permanently revokes an input device fd (`EVIOCREVOKE`); there is no way to undo this, the
+/// device must be re-opened.
+pub const EVIOCREVOKE: u64 = 0x4004_4591;
+
+/// Mirrors the kernel's `struct vt_mode`, used with [`VT_SETMODE`] to ask to be notified (instead
+/// of being switched away from immediately) when another process requests this VT.
+#[repr(C)]
+pub struct vt_mode {
+    pub mode: i8,
+    pub waitv: i8,
+    pub relsig: i16,
+    pub acqsig: i16,
+    pub frsig: i16,
+}
+
+/// Mirrors the kernel's `struct epoll_event`, which is packed on x86-64 for compatibility with
+/// 32-bit user space.
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+pub struct epoll_event {
+    pub events: u32,
+    pub data: u64,
+}
+
+/// Mirrors the fixed-size header of the kernel's `struct inotify_event`; the variable-length,
+/// NUL-padded `name` field follows immediately after it in the read buffer.
+#[repr(C)]
+pub struct inotify_event {
+    pub wd: i32,
+    pub mask: u32,
+    pub cookie: u32,
+    pub len: u32,
+}
+
+#[repr(C)]
+pub struct timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+/// The kernel's `sigset_t`: a plain 64-bit bitmask (one bit per signal), unlike glibc's
+/// larger opaque one.
+#[repr(transparent)]
+#[derive(Copy, Clone)]
+pub struct sigset_t(u64);
+
+impl sigset_t {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn with(mut self, signal: i32) -> Self {
+        self.0 |= 1 << (signal - 1);
+        self
+    }
+}
+
+/// Mirrors the kernel's `struct signalfd_siginfo`, which is always 128 bytes regardless of which
+/// fields are meaningful for a given signal; only `ssi_signo` is needed here.
+#[repr(C)]
+pub struct signalfd_siginfo {
+    pub ssi_signo: u32,
+    _rest: [u8; 124],
+}
+
 #[repr(C)]
 pub struct nlmsgerr {
     pub error: i32,
@@ -169,6 +289,33 @@ unsafe fn syscall_5(num: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5:
     ret
 }
 
+#[allow(clippy::too_many_arguments)]
+unsafe fn syscall_6(
+    num: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+    arg6: u64,
+) -> i64 {
+    let ret;
+    asm!(
+        "syscall",
+        in("rax") num,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        in("rdx") arg3,
+        in("r10") arg4,
+        in("r8") arg5,
+        in("r9") arg6,
+        lateout("rax") ret,
+        out("rcx") _,
+        out("r11") _,
+    );
+    ret
+}
+
 pub fn read(fd: u32, buf: &mut [u8]) -> i64 {
     unsafe { syscall_3(0, fd.into(), buf.as_mut_ptr() as u64, buf.len() as u64) }
 }
@@ -177,6 +324,11 @@ pub fn write(fd: u32, buf: &[u8]) -> i64 {
     unsafe { syscall_3(1, fd.into(), buf.as_ptr() as u64, buf.len() as u64) }
 }
 
+/// Fills `buf` with random bytes from the kernel CSPRNG.
+pub fn getrandom(buf: &mut [u8]) -> i64 {
+    unsafe { syscall_3(318, buf.as_mut_ptr() as u64, buf.len() as u64, 0) }
+}
+
 #[allow(clippy::missing_safety_doc)]
 pub unsafe fn open(filename: *const u8, flags: u32, mode: u32) -> i32 {
     syscall_3(2, filename as u64, flags.into(), mode.into()) as i32
@@ -186,6 +338,11 @@ pub fn close(fd: u32) -> i32 {
     unsafe { syscall_1(3, fd.into()) as i32 }
 }
 
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn ioctl(fd: u32, request: u64, arg: u64) -> i32 {
+    syscall_3(16, fd.into(), request, arg) as i32
+}
+
 pub fn dup2(old_fd: u32, new_fd: u32) -> i32 {
     unsafe { syscall_2(33, old_fd.into(), new_fd.into()) as i32 }
 }
@@ -194,6 +351,63 @@ pub fn socket(family: i32, sock_type: i32, protocol: i32) -> i32 {
     unsafe { syscall_3(41, family as u64, sock_type as u64, protocol as u64) as i32 }
 }
 
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn bind(fd: u32, addr: *const u8, addrlen: u32) -> i32 {
+    syscall_3(49, fd.into(), addr as u64, addrlen.into()) as i32
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn sendto(
+    fd: u32,
+    buf: *const u8,
+    len: usize,
+    flags: i32,
+    dest_addr: *const u8,
+    addrlen: u32,
+) -> i64 {
+    syscall_6(
+        44,
+        fd.into(),
+        buf as u64,
+        len as u64,
+        flags as u64,
+        dest_addr as u64,
+        addrlen.into(),
+    )
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn recvfrom(
+    fd: u32,
+    buf: *mut u8,
+    len: usize,
+    flags: i32,
+    src_addr: *mut u8,
+    addrlen: *mut u32,
+) -> i64 {
+    syscall_6(
+        45,
+        fd.into(),
+        buf as u64,
+        len as u64,
+        flags as u64,
+        src_addr as u64,
+        addrlen as u64,
+    )
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn setsockopt(fd: u32, level: i32, optname: i32, optval: *const u8, optlen: u32) -> i32 {
+    syscall_5(
+        54,
+        fd.into(),
+        level as u64,
+        optname as u64,
+        optval as u64,
+        optlen.into(),
+    ) as i32
+}
+
 #[allow(clippy::missing_safety_doc)]
 pub unsafe fn clone(
     flags: u64,
@@ -233,6 +447,45 @@ pub unsafe fn clone(
     ret
 }
 
+/// Maximum number of times to call `clone` (the first attempt included) when it keeps failing
+/// transiently, before giving up and returning the error to the caller.
+const CLONE_RETRY_ATTEMPTS: u32 = 10;
+/// Backoff delay cap, matching the few-millisecond ceiling std's `process_unix` backs off to
+/// around `fork`/`clone` under memory pressure.
+const CLONE_RETRY_MAX_DELAY_NS: i64 = 4_000_000;
+
+/// Like [`clone`], but retries with an exponentially increasing delay (starting at 1 ns, doubling
+/// up to [`CLONE_RETRY_MAX_DELAY_NS`]) when it fails transiently with `EAGAIN` or `ENOMEM`, which
+/// can happen under memory pressure during early boot. Any other error is returned immediately.
+///
+/// # Safety
+///
+/// Same as [`clone`].
+#[allow(clippy::missing_safety_doc, clippy::too_many_arguments)]
+unsafe fn clone_with_retry(
+    flags: u64,
+    sp: *mut u8,
+    tid_parent: *mut i32,
+    tid_child: *mut i32,
+    tls: *mut u8,
+    f: unsafe fn(data: usize),
+    arg: usize,
+) -> i32 {
+    let mut delay_ns: i64 = 1;
+    for attempt in 0..CLONE_RETRY_ATTEMPTS {
+        let ret = clone(flags, sp, tid_parent, tid_child, tls, f, arg);
+        if ret >= 0 || (ret != -EAGAIN && ret != -ENOMEM) || attempt + 1 == CLONE_RETRY_ATTEMPTS {
+            return ret;
+        }
+        nanosleep(&timespec {
+            tv_sec: 0,
+            tv_nsec: delay_ns,
+        });
+        delay_ns = (delay_ns * 2).min(CLONE_RETRY_MAX_DELAY_NS);
+    }
+    unreachable!()
+}
+
 #[allow(clippy::missing_safety_doc)]
 pub unsafe fn execve(filename: *const u8, argv: *const *const u8, envp: *const *const u8) -> i32 {
     syscall_3(59, filename as u64, argv as u64, envp as u64) as i32
@@ -288,6 +541,22 @@ pub fn sync() {
     unsafe { syscall_0(162) };
 }
 
+/// Sleeps for `req`, ignoring early wakeups from signals (the remaining time, which would
+/// normally be written back through a second argument, is simply discarded).
+pub fn nanosleep(req: &timespec) {
+    unsafe { syscall_2(35, req as *const timespec as u64, 0) };
+}
+
+pub const CLOCK_MONOTONIC: i32 = 1;
+
+/// Reads the current time off `clockid` (e.g. [`CLOCK_MONOTONIC`]) into `ts`.
+///
+/// # Safety
+/// `ts` must point to valid, writable memory for a [`timespec`].
+pub unsafe fn clock_gettime(clockid: i32, ts: *mut timespec) -> i32 {
+    syscall_2(228, clockid as u64, ts as u64) as i32
+}
+
 #[allow(clippy::missing_safety_doc)]
 pub unsafe fn mount(
     dev_name: *const u8,
@@ -316,6 +585,64 @@ pub unsafe fn reboot(magic1: i32, magic2: i32, cmd: u32, arg: *const u8) -> i32
     syscall_4(169, magic1 as u64, magic2 as u64, cmd as u64, arg as u64) as i32
 }
 
+pub fn epoll_wait(epfd: u32, events: &mut [epoll_event], timeout: i32) -> i32 {
+    unsafe {
+        syscall_4(
+            232,
+            epfd.into(),
+            events.as_mut_ptr() as u64,
+            events.len() as u64,
+            timeout as i64 as u64,
+        ) as i32
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn epoll_ctl(epfd: u32, op: i32, fd: u32, event: *mut epoll_event) -> i32 {
+    syscall_4(233, epfd.into(), op as u64, fd.into(), event as u64) as i32
+}
+
+pub fn epoll_create1(flags: i32) -> i32 {
+    unsafe { syscall_1(291, flags as u64) as i32 }
+}
+
+pub fn inotify_init1(flags: i32) -> i32 {
+    unsafe { syscall_1(294, flags as u64) as i32 }
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn inotify_add_watch(fd: u32, pathname: *const u8, mask: u32) -> i32 {
+    syscall_3(254, fd.into(), pathname as u64, mask.into()) as i32
+}
+
+/// Changes the calling thread's signal mask. `how` is one of the `SIG_*` constants.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn rt_sigprocmask(how: i32, set: &sigset_t) -> i32 {
+    syscall_4(
+        14,
+        how as u64,
+        set as *const sigset_t as u64,
+        0,
+        mem::size_of::<sigset_t>() as u64,
+    ) as i32
+}
+
+/// Creates (or, with `fd >= 0`, updates the mask of) a file descriptor that becomes readable with
+/// a [`signalfd_siginfo`] record for every signal in `mask` that would otherwise have been
+/// delivered to this thread. The signals in `mask` must already be blocked with
+/// [`rt_sigprocmask`], or they can still be delivered the normal way instead.
+pub fn signalfd4(fd: i32, mask: &sigset_t, flags: i32) -> i32 {
+    unsafe {
+        syscall_4(
+            289,
+            fd as u64,
+            mask as *const sigset_t as u64,
+            mem::size_of::<sigset_t>() as u64,
+            flags as u64,
+        ) as i32
+    }
+}
+
 pub struct Fd(pub u32);
 
 impl Drop for Fd {
@@ -349,99 +676,119 @@ impl fmt::Write for Stderr {
     }
 }
 
-struct SpawnHelperData {
-    filename: *const u8,
-    argv: *const *const u8,
-    envp: *const *const u8,
-    pre_exec: unsafe fn(data: usize) -> bool,
-    pre_exec_data: usize,
+/// A single action performed, in order, in the child before `execve`. This is the
+/// `posix_spawn_file_actions`/std's `process_common` model, recast for our raw-syscall spawner,
+/// so that setup like "dup this fd onto stdout" or "drop to this uid" is data instead of a
+/// one-off closure.
+pub enum FileAction<'a> {
+    Dup2 { from: u32, to: u32 },
+    Chdir(*const u8),
+    SetUid(u32),
+    SetGid(u32),
+    SetGroups(&'a [u32]),
+}
+
+impl FileAction<'_> {
+    /// Applies this action, returning `false` if it failed.
+    fn apply(&self) -> bool {
+        let ret = match *self {
+            FileAction::Dup2 { from, to } => dup2(from, to),
+            FileAction::Chdir(path) => unsafe { chdir(path) },
+            FileAction::SetUid(uid) => setuid(uid),
+            FileAction::SetGid(gid) => setgid(gid),
+            FileAction::SetGroups(groups) => setgroups(groups),
+        };
+        ret >= 0
+    }
+}
+
+/// Declarative description of a process to spawn: the executable, its argv/envp, and an ordered
+/// list of [`FileAction`]s to run in the child before `execve`.
+pub struct ProcessConfig<'a> {
+    pub filename: *const u8,
+    pub argv: *const *const u8,
+    pub envp: *const *const u8,
+    pub actions: &'a [FileAction<'a>],
 }
 
 unsafe fn spawn_helper(arg: usize) {
-    let arg = &*(arg as *const SpawnHelperData);
-    if (arg.pre_exec)(arg.pre_exec_data) {
-        let ret = execve(arg.filename, arg.argv, arg.envp);
-        if ret < 0 {
-            // Do not panic.
-            let _ = writeln!(Stderr, "failed to execve: {ret}");
+    let config = &*(arg as *const ProcessConfig);
+    for action in config.actions {
+        if !action.apply() {
+            exit(1);
         }
     }
+    let ret = execve(config.filename, config.argv, config.envp);
+    if ret < 0 {
+        // Do not panic.
+        let _ = writeln!(Stderr, "failed to execve: {ret}");
+    }
     exit(1);
 }
 
-/// Spawns a new process and returns its PID. The `pre_exec` function is called with the
-/// `pre_exec_data` argument before `execve` is called. This allows the caller to change the
-/// environment for the new process.
+/// Spawns a new process and returns its PID.
 ///
 /// # Safety
 ///
-/// `filename` must be a NUL-terminated string.
-/// `argv` must be an array of NUL-terminated strings, with a null pointer at the end.
-/// `envp` must be an array of NUL-terminated strings, with a null pointer at the end.
-/// `pre_exec` must not introduce UB.
-pub unsafe fn spawn_with_pre_exec(
-    filename: *const u8,
-    argv: *const *const u8,
-    envp: *const *const u8,
-    pre_exec: unsafe fn(data: usize) -> bool,
-    pre_exec_data: usize,
-) -> i32 {
+/// `config.filename` must be a NUL-terminated string.
+/// `config.argv` must be an array of NUL-terminated strings, with a null pointer at the end.
+/// `config.envp` must be an array of NUL-terminated strings, with a null pointer at the end.
+pub unsafe fn spawn(config: &ProcessConfig) -> i32 {
     let mut stack = [0u8; 512];
     // The stack grows downwards.
     let mut sp = stack.as_mut_ptr().add(stack.len());
     // The stack must be 16-byte aligned.
     sp = (sp as usize & !0xf) as *mut u8;
-    let data = SpawnHelperData {
-        filename,
-        argv,
-        envp,
-        pre_exec,
-        pre_exec_data,
-    };
-    clone(
+    clone_with_retry(
         CLONE_VM | CLONE_VFORK | SIGCHLD as u64,
         sp,
         ptr::null_mut(),
         ptr::null_mut(),
         ptr::null_mut(),
         spawn_helper,
-        &data as *const _ as usize,
+        config as *const _ as usize,
     )
 }
 
-fn dummy_pre_exec(_data: usize) -> bool {
-    true
-}
-
-/// Spawns a new process and returns its PID.
+/// Spawns a new process and returns its PID together with a pidfd (see `pidfd_open(2)`) that
+/// becomes readable (`POLLIN`) exactly once, when the process exits. A single `wait4(pid, …)`
+/// then reaps it without needing to catch `SIGCHLD`, unless a `wait4(-1, …)` call elsewhere has
+/// already reaped it, in which case it fails with `ECHILD`.
 ///
 /// # Safety
 ///
-/// `filename` must be a NUL-terminated string.
-/// `argv` must be an array of NUL-terminated strings, with a null pointer at the end.
-/// `envp` must be an array of NUL-terminated strings, with a null pointer at the end.
-pub unsafe fn spawn(filename: *const u8, argv: *const *const u8, envp: *const *const u8) -> i32 {
-    spawn_with_pre_exec(filename, argv, envp, dummy_pre_exec, 0)
+/// Same as [`spawn`].
+pub unsafe fn spawn_pidfd(config: &ProcessConfig) -> Result<(i32, i32), i32> {
+    let mut stack = [0u8; 512];
+    // The stack grows downwards.
+    let mut sp = stack.as_mut_ptr().add(stack.len());
+    // The stack must be 16-byte aligned.
+    sp = (sp as usize & !0xf) as *mut u8;
+    // With `CLONE_PIDFD`, the kernel writes the new pidfd into the memory pointed to by
+    // `tid_parent` instead of the child's TID.
+    let mut pidfd: i32 = -1;
+    let pid = clone_with_retry(
+        CLONE_VM | CLONE_VFORK | CLONE_PIDFD | SIGCHLD as u64,
+        sp,
+        &mut pidfd as *mut i32,
+        ptr::null_mut(),
+        ptr::null_mut(),
+        spawn_helper,
+        config as *const _ as usize,
+    );
+    if pid < 0 {
+        return Err(pid);
+    }
+    Ok((pid, pidfd))
 }
 
-/// Spawns a new process, waits for it to die and returns its status code. The `pre_exec` function
-/// is called with the `pre_exec_data` argument before `execve` is called. This allows the caller
-/// to change the environment for the new process.
+/// Spawns a new process, waits for it to die and returns its status code.
 ///
 /// # Safety
 ///
-/// `filename` must be a NUL-terminated string.
-/// `argv` must be an array of NUL-terminated strings, with a null pointer at the end.
-/// `envp` must be an array of NUL-terminated strings, with a null pointer at the end.
-/// `pre_exec` must not introduce UB.
-pub unsafe fn spawn_and_wait_with_pre_exec(
-    filename: *const u8,
-    argv: *const *const u8,
-    envp: *const *const u8,
-    pre_exec: unsafe fn(data: usize) -> bool,
-    pre_exec_data: usize,
-) -> Result<i32, i32> {
-    let pid = spawn_with_pre_exec(filename, argv, envp, pre_exec, pre_exec_data);
+/// Same as [`spawn`].
+pub unsafe fn spawn_and_wait(config: &ProcessConfig) -> Result<i32, i32> {
+    let pid = spawn(config);
     if pid < 0 {
         return Err(pid);
     }
@@ -452,18 +799,3 @@ pub unsafe fn spawn_and_wait_with_pre_exec(
     }
     Ok(status)
 }
-
-/// Spawns a new process, waits for it to die and returns its status code.
-///
-/// # Safety
-///
-/// `filename` must be a NUL-terminated string.
-/// `argv` must be an array of NUL-terminated strings, with a null pointer at the end.
-/// `envp` must be an array of NUL-terminated strings, with a null pointer at the end.
-pub unsafe fn spawn_and_wait(
-    filename: *const u8,
-    argv: *const *const u8,
-    envp: *const *const u8,
-) -> Result<i32, i32> {
-    spawn_and_wait_with_pre_exec(filename, argv, envp, dummy_pre_exec, 0)
-}
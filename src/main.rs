@@ -7,20 +7,20 @@
 
 use core::convert::{TryFrom, TryInto};
 use core::fmt::Write;
-use core::mem;
 use core::{panic::PanicInfo, ptr};
 
 pub mod config;
+pub mod dhcp;
+pub mod hotplug;
 pub mod linux;
 pub mod mounts;
 pub mod net;
 pub mod seat;
 pub mod shutdown;
-pub mod sysctl;
 pub mod ui;
 
 fn late_init() {
-    sysctl::apply_sysctl();
+    config::apply_sysctl();
 
     let mut ret = config::mount_late();
     if ret < 0 {
@@ -56,16 +56,6 @@ fn redirect_stdout() {
     }
 }
 
-fn dmesg_pre_exec(fd: usize) -> bool {
-    // Output into the FD.
-    let ret = linux::dup2(fd.try_into().unwrap(), 1);
-    if ret < 0 {
-        writeln!(linux::Stderr, "failed to dup log FD for dmesg: {ret}").unwrap();
-        return false;
-    }
-    true
-}
-
 fn write_kernel_log() {
     let fd = unsafe {
         linux::open(
@@ -81,15 +71,13 @@ fn write_kernel_log() {
             return;
         }
     };
-    let ret = unsafe {
-        linux::spawn_and_wait_with_pre_exec(
-            b"/bin/dmesg\0" as *const u8,
-            &[b"/bin/dmesg\0" as *const u8, ptr::null()] as *const *const u8,
-            &[ptr::null()] as *const *const u8,
-            dmesg_pre_exec,
-            fd.0.try_into().unwrap(),
-        )
+    let config = linux::ProcessConfig {
+        filename: b"/bin/dmesg\0" as *const u8,
+        argv: &[b"/bin/dmesg\0" as *const u8, ptr::null()] as *const *const u8,
+        envp: &[ptr::null()] as *const *const u8,
+        actions: &[linux::FileAction::Dup2 { from: fd.0, to: 1 }],
     };
+    let ret = unsafe { linux::spawn_and_wait(&config) };
     match ret {
         Ok(code) => {
             if code != 0 {
@@ -150,43 +138,49 @@ fn create_dev_symlinks() {
     }
 }
 
-fn add_dri_render_permissions() {
-    let ret = unsafe {
-        linux::chown(
-            b"/dev/dri/renderD128\0" as *const u8,
-            config::USER_UID,
-            config::USER_GID,
-        )
-    };
-    if ret < 0 {
-        writeln!(linux::Stderr, "failed to chown /dev/dri/renderD128: {ret}").unwrap();
+/// Drains every pending [`linux::signalfd_siginfo`] record from `fd` without looking at their
+/// contents: we only ever block `SIGCHLD` on it, so there is nothing to learn beyond "a child
+/// exited", and [`reap_other_children`] is what actually finds out which one.
+fn drain_sigchld_fd(fd: u32) {
+    loop {
+        let mut info = core::mem::MaybeUninit::<linux::signalfd_siginfo>::uninit();
+        let n = linux::read(fd, unsafe {
+            core::slice::from_raw_parts_mut(
+                info.as_mut_ptr() as *mut u8,
+                core::mem::size_of::<linux::signalfd_siginfo>(),
+            )
+        });
+        if n == -i64::from(linux::EAGAIN) {
+            return;
+        } else if n < 0 {
+            writeln!(linux::Stderr, "failed to read signalfd: {n}").unwrap();
+            return;
+        }
     }
 }
 
-fn run_event_loop() {
-    let mask = linux::sigset_t::try_from(1 << (linux::SIGCHLD - 1)).unwrap();
-
-    let ret = linux::rt_sigprocmask(
-        linux::SIG_BLOCK,
-        &mask,
-        ptr::null_mut(),
-        mem::size_of_val(&mask),
-    );
-    if ret < 0 {
-        writeln!(linux::Stderr, "failed to block SIGCHLD: {ret}").unwrap();
-    }
-
-    let signalfd = linux::signalfd4(-1, mask, linux::SFD_CLOEXEC | linux::SFD_NONBLOCK);
-    if signalfd < 0 {
-        writeln!(
-            linux::Stderr,
-            "failed to create SIGCHLD signalfd: {signalfd}"
-        )
-        .unwrap();
-        return;
+/// Reaps every zombie child, logging the exit status of all but `ui_child_pid`. The UI child is
+/// normally reaped separately once its pidfd reports it as dead, but if it has already exited
+/// by the time this runs, it is reaped here too.
+fn reap_other_children(ui_child_pid: i32) {
+    loop {
+        let mut status: i32 = 0;
+        let pid = unsafe {
+            linux::wait4(-1, &mut status as *mut i32, linux::WNOHANG, ptr::null_mut())
+        };
+        if pid <= 0 {
+            if pid < 0 && pid != -linux::ECHILD {
+                writeln!(linux::Stderr, "failed to reap child: {pid}").unwrap();
+            }
+            return;
+        }
+        if pid != ui_child_pid {
+            writeln!(linux::Stdout, "reaped child {pid}: {status}").unwrap();
+        }
     }
-    let signalfd = linux::Fd(signalfd.try_into().unwrap());
+}
 
+fn run_event_loop() {
     let (mut seat_server, seat_compositor_fd) = match seat::SeatServer::new() {
         Ok(t) => t,
         Err(err) => {
@@ -194,95 +188,130 @@ fn run_event_loop() {
             return;
         }
     };
+    let _ = seat_compositor_fd;
 
-    let ui_child_pid = ui::start_ui_process(seat_compositor_fd.0);
-    if ui_child_pid < 0 {
-        writeln!(linux::Stderr, "failed to start UI process: {ui_child_pid}").unwrap();
-        return;
-    }
+    // Registered before the UI process (and the udev coldplug trigger it causes) is started, so
+    // that no device node creation can be missed.
+    let hotplug_watcher = match hotplug::HotplugWatcher::new() {
+        Ok(val) => val,
+        Err(err) => {
+            writeln!(linux::Stderr, "failed to create hotplug watcher: {err}").unwrap();
+            return;
+        }
+    };
+
+    let (ui_child_pid, ui_child_pidfd) = match ui::start_ui_process() {
+        Ok(t) => t,
+        Err(ret) => {
+            writeln!(linux::Stderr, "failed to start UI process: {ret}").unwrap();
+            return;
+        }
+    };
+    let ui_child_pidfd = linux::Fd(ui_child_pidfd.try_into().unwrap());
 
     late_init();
 
-    loop {
-        let mut fds = [
-            linux::pollfd {
-                fd: i32::try_from(signalfd.0).unwrap(),
-                events: linux::POLLIN,
-                revents: 0,
-            },
-            linux::pollfd {
-                fd: i32::try_from(seat_server.fd()).unwrap(),
-                events: linux::POLLIN,
-                revents: 0,
-            },
-        ];
-        let ret = linux::poll(&mut fds, 500);
+    // Block SIGCHLD before creating the signalfd: otherwise its default disposition (ignore)
+    // would discard a delivery instead of leaving it pending for the signalfd to report.
+    let sigchld_mask = linux::sigset_t::empty().with(linux::SIGCHLD);
+    let ret = unsafe { linux::rt_sigprocmask(linux::SIG_BLOCK, &sigchld_mask) };
+    if ret < 0 {
+        writeln!(linux::Stderr, "failed to block SIGCHLD: {ret}").unwrap();
+        return;
+    }
+    let sigchld_fd = linux::signalfd4(-1, &sigchld_mask, linux::SFD_NONBLOCK | linux::SFD_CLOEXEC);
+    if sigchld_fd < 0 {
+        writeln!(linux::Stderr, "failed to create signalfd: {sigchld_fd}").unwrap();
+        return;
+    }
+    let sigchld_fd = linux::Fd(sigchld_fd.try_into().unwrap());
+
+    let epfd = linux::epoll_create1(linux::EPOLL_CLOEXEC);
+    if epfd < 0 {
+        writeln!(linux::Stderr, "failed to create epoll instance: {epfd}").unwrap();
+        return;
+    }
+    let epfd = linux::Fd(epfd.try_into().unwrap());
+
+    let seat_server_fd = seat_server.fd();
+    let seat_vt_signal_fd = seat_server.vt_signal_fd();
+    let hotplug_watcher_fd = hotplug_watcher.fd();
+    // Edge-triggered (`EPOLLET`) so that, unlike with level-triggered `poll`, we don't need to
+    // drain each fd in a loop just to stop it from immediately waking us up again: we are only
+    // notified once per new batch of data, and draining happens naturally as we read.
+    for fd in [
+        ui_child_pidfd.0,
+        seat_server_fd,
+        seat_vt_signal_fd,
+        hotplug_watcher_fd,
+        sigchld_fd.0,
+    ] {
+        let mut event = linux::epoll_event {
+            events: linux::EPOLLIN | linux::EPOLLET,
+            data: fd.into(),
+        };
+        let ret =
+            unsafe { linux::epoll_ctl(epfd.0, linux::EPOLL_CTL_ADD, fd, &mut event as *mut _) };
         if ret < 0 {
-            writeln!(linux::Stderr, "failed to poll: {ret}").unwrap();
-            break;
-        }
-        if fds[0].revents & (linux::POLLERR | linux::POLLNVAL) != 0 {
-            writeln!(
-                linux::Stderr,
-                "poll returned error on SIGCHLD signalfd: {}",
-                fds[0].revents
-            )
-            .unwrap();
-            break;
+            writeln!(linux::Stderr, "failed to register fd {fd} with epoll: {ret}").unwrap();
+            return;
         }
-        if fds[1].revents & (linux::POLLERR | linux::POLLNVAL) != 0 {
-            writeln!(
-                linux::Stderr,
-                "poll returned error on seat server socket: {}",
-                fds[1].revents
-            )
-            .unwrap();
+    }
+
+    loop {
+        let mut events = [linux::epoll_event { events: 0, data: 0 }; 5];
+        let n = linux::epoll_wait(epfd.0, &mut events, -1);
+        if n < 0 {
+            writeln!(linux::Stderr, "failed to epoll_wait: {n}").unwrap();
             break;
         }
 
-        if fds[0].revents & linux::POLLIN != 0 {
-            // Drain the signalfd before we reap processes to mark the signals as handled by the
-            // kernel so that it doesn't wake up until a new one arrives. If poll was
-            // edge-triggered, we would not need to do that, but here we need to do it because it
-            // is level-triggered.
-            loop {
-                let mut buf = [0u8; 128];
-                let ret = linux::read(signalfd.0, &mut buf);
-                if ret == -i64::from(linux::EAGAIN) {
-                    break;
-                } else if ret < 0 {
-                    writeln!(linux::Stderr, "failed to read from signalfd: {ret}").unwrap();
-                    break;
-                }
+        for event in &events[..n as usize] {
+            let fd = u32::try_from(event.data).unwrap();
+            let events = event.events;
+
+            if events & (linux::EPOLLERR | linux::EPOLLHUP) != 0 {
+                writeln!(linux::Stderr, "epoll returned error on fd {fd}: {events}").unwrap();
+                return;
             }
 
-            // Reap zombie processes.
-            let mut status: i32 = 0;
-            loop {
-                let pid = unsafe {
-                    linux::wait4(-1, &mut status as *mut i32, linux::WNOHANG, ptr::null_mut())
+            if fd == ui_child_pidfd.0 {
+                // The pidfd became readable, which the kernel guarantees happens exactly when
+                // the process has exited. It may already have been reaped by
+                // `reap_other_children` below if both events were reported in the same batch, in
+                // which case `wait4` returns `ECHILD`, which is not an error here.
+                let mut status: i32 = 0;
+                let ret = unsafe {
+                    linux::wait4(
+                        ui_child_pid,
+                        &mut status as *mut i32,
+                        linux::WNOHANG,
+                        ptr::null_mut(),
+                    )
                 };
-                if pid < 0 {
-                    writeln!(linux::Stderr, "failed to wait for process: {pid}").unwrap();
-                    break;
-                } else if pid == 0 {
-                    break;
-                } else if pid == ui_child_pid {
+                if ret > 0 {
                     writeln!(linux::Stdout, "UI process died: {status}").unwrap();
-                    // Consider the system stopped when the UI process dies.
-                    return;
+                } else if ret < 0 && ret != -linux::ECHILD {
+                    writeln!(linux::Stderr, "failed to wait for UI process: {ret}").unwrap();
                 }
-            }
-        }
-
-        if fds[1].revents & linux::POLLIN != 0 {
-            if let Err(err) = seat_server.process_incoming() {
-                writeln!(
-                    linux::Stderr,
-                    "failed to process seat server request: {err}"
-                )
-                .unwrap();
+                // Consider the system stopped when the UI process dies.
                 return;
+            } else if fd == seat_server_fd {
+                if let Err(err) = seat_server.process_incoming() {
+                    writeln!(
+                        linux::Stderr,
+                        "failed to process seat server request: {err}"
+                    )
+                    .unwrap();
+                    return;
+                }
+            } else if fd == seat_vt_signal_fd {
+                seat_server.process_vt_signal();
+            } else if fd == hotplug_watcher_fd {
+                hotplug_watcher.process_events();
+            } else if fd == sigchld_fd.0 {
+                drain_sigchld_fd(sigchld_fd.0);
+                reap_other_children(ui_child_pid);
             }
         }
     }
@@ -300,7 +329,6 @@ extern "C" fn _start() -> ! {
     }
 
     create_dev_symlinks();
-    add_dri_render_permissions();
 
     run_event_loop();
 
@@ -8,9 +8,39 @@ enum MountParserState {
     AfterDirectory,
 }
 
+/// Decodes 3 ASCII octal digits (as found after a `\` in `/proc/mounts`) into the byte they
+/// represent, or `None` if any of them is not an octal digit or the value does not fit in a
+/// byte.
+fn decode_octal_escape(digits: [u8; 3]) -> Option<u8> {
+    let mut val: u16 = 0;
+    for d in digits {
+        if !(b'0'..=b'7').contains(&d) {
+            return None;
+        }
+        val = val * 8 + u16::from(d - b'0');
+    }
+    u8::try_from(val).ok()
+}
+
+/// Appends a single byte to `out` at `cursor`, bumping `cursor`. Returns `-linux::ENOMEM` if
+/// `out` is full.
+fn push_byte<const N: usize>(out: &mut [u8; N], cursor: &mut usize, byte: u8) -> i32 {
+    if *cursor >= out.len() {
+        return -linux::ENOMEM;
+    }
+    out[*cursor] = byte;
+    *cursor += 1;
+    0
+}
+
 fn read_mounts_from_fd<const N: usize>(fd: u32, out: &mut [u8; N]) -> i32 {
     let mut state = MountParserState::BeforeDirectory;
     let mut cursor = 0;
+    // The kernel escapes space, tab, newline and backslash in the device and mountpoint fields
+    // as `\` followed by 3 octal digits. A `read` may return in the middle of such an escape, so
+    // the digits seen so far have to be carried over to the next `read`.
+    let mut escape_digits = [0u8; 3];
+    let mut escape_len = 0usize;
     loop {
         let mut buf = [0u8; 128];
         let n = unsafe { linux::read(fd, buf.as_mut_ptr(), buf.len()) };
@@ -22,72 +52,77 @@ fn read_mounts_from_fd<const N: usize>(fd: u32, out: &mut [u8; N]) -> i32 {
         }
         let n = usize::try_from(n).unwrap();
 
-        let mut done = 0;
-        loop {
-            let remaining = &buf[done..n];
+        for &b in &buf[..n] {
             match state {
                 MountParserState::BeforeDirectory => {
-                    match remaining.iter().position(|b| *b == b' ') {
-                        Some(p) => {
-                            state = MountParserState::Directory;
-
-                            done += p + 1;
-                            if done >= buf.len() {
-                                break;
-                            }
-                        }
-                        None => break,
+                    if b == b' ' {
+                        state = MountParserState::Directory;
                     }
                 }
-                MountParserState::Directory => match remaining.iter().position(|b| *b == b' ') {
-                    Some(p) => {
-                        if cursor + p + 1 >= out.len() {
-                            return -linux::ENOMEM;
+                MountParserState::Directory => {
+                    if escape_len > 0 {
+                        escape_digits[escape_len - 1] = b;
+                        escape_len += 1;
+                        if escape_len == escape_digits.len() + 1 {
+                            let ret = match decode_octal_escape(escape_digits) {
+                                Some(decoded) => push_byte(out, &mut cursor, decoded),
+                                // Malformed escape: treat it as a literal backslash followed by
+                                // whatever we collected.
+                                None => {
+                                    let mut ret = push_byte(out, &mut cursor, b'\\');
+                                    for d in escape_digits {
+                                        if ret < 0 {
+                                            break;
+                                        }
+                                        ret = push_byte(out, &mut cursor, d);
+                                    }
+                                    ret
+                                }
+                            };
+                            if ret < 0 {
+                                return ret;
+                            }
+                            escape_len = 0;
                         }
-
-                        out[cursor..(cursor + p)].copy_from_slice(&remaining[..p]);
-                        cursor += p;
-
-                        out[cursor] = b'\0';
-                        cursor += 1;
-
-                        state = MountParserState::AfterDirectory;
-
-                        done += p + 1;
-                        if done >= buf.len() {
-                            break;
+                    } else if b == b'\\' {
+                        escape_len = 1;
+                    } else if b == b' ' {
+                        let ret = push_byte(out, &mut cursor, b'\0');
+                        if ret < 0 {
+                            return ret;
                         }
-                    }
-                    None => {
-                        if cursor + remaining.len() + 1 >= out.len() {
-                            return -linux::ENOMEM;
+                        state = MountParserState::AfterDirectory;
+                    } else {
+                        let ret = push_byte(out, &mut cursor, b);
+                        if ret < 0 {
+                            return ret;
                         }
-                        out[cursor..(cursor + remaining.len())].copy_from_slice(&remaining);
-                        cursor += remaining.len();
-
-                        out[cursor] = b'\0';
-                        cursor += 1;
-
-                        break;
                     }
-                },
+                }
                 MountParserState::AfterDirectory => {
-                    match remaining.iter().position(|b| *b == b'\n') {
-                        Some(p) => {
-                            state = MountParserState::BeforeDirectory;
-
-                            done += p + 1;
-                            if done >= buf.len() {
-                                break;
-                            }
-                        }
-                        None => break,
+                    if b == b'\n' {
+                        state = MountParserState::BeforeDirectory;
                     }
                 }
             }
         }
     }
 
+    // A short/malformed escape left dangling at EOF is treated as a literal backslash followed
+    // by whatever digits were collected.
+    if escape_len > 0 {
+        let mut ret = push_byte(out, &mut cursor, b'\\');
+        for d in &escape_digits[..(escape_len - 1)] {
+            if ret < 0 {
+                break;
+            }
+            ret = push_byte(out, &mut cursor, *d);
+        }
+        if ret < 0 {
+            return ret;
+        }
+    }
+
     cursor.try_into().unwrap()
 }
 
@@ -4,13 +4,64 @@
 
 use core::convert::TryFrom;
 use core::convert::TryInto;
+use core::fmt::{self, Write};
 use core::slice;
 use core::{mem, ptr};
 
 use crate::config;
+use crate::dhcp;
 use crate::linux;
 
-pub type Ipv4Addr = u32;
+/// How long to wait for an interface to report carrier before adding its default route.
+const CARRIER_TIMEOUT_MS: i32 = 5_000;
+
+/// An IPv4 or IPv6 address, stored as raw octets in the same network byte order rtnetlink
+/// attributes carry them in, so no conversion is needed when building a request out of one.
+#[derive(Copy, Clone)]
+pub enum IpAddr {
+    V4([u8; 4]),
+    V6([u8; 16]),
+}
+
+impl IpAddr {
+    /// The `AF_INET`/`AF_INET6` value to put in `ifa_family`/`rtm_family` for this address.
+    fn family(&self) -> u8 {
+        u8::try_from(match self {
+            IpAddr::V4(_) => libc::AF_INET,
+            IpAddr::V6(_) => libc::AF_INET6,
+        })
+        .unwrap()
+    }
+}
+
+/// Formats an IPv4 address as a dotted quad, or an IPv6 address as its (uncompressed) colon-
+/// separated groups, for `/etc/resolv.conf`/`/etc/hosts`.
+impl fmt::Display for IpAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpAddr::V4(o) => write!(f, "{}.{}.{}.{}", o[0], o[1], o[2], o[3]),
+            IpAddr::V6(o) => {
+                for i in 0..8 {
+                    if i > 0 {
+                        write!(f, ":")?;
+                    }
+                    write!(f, "{:x}", u16::from_be_bytes([o[i * 2], o[i * 2 + 1]]))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Mirrors the kernel's `struct sockaddr_nl`, the address type passed to `bind()` for an
+/// `AF_NETLINK` socket.
+#[repr(C)]
+struct sockaddr_nl {
+    nl_family: u16,
+    nl_pad: u16,
+    nl_pid: u32,
+    nl_groups: u32,
+}
 
 /// A netlink socket FD with automatic cleanup and that keeps track of the
 /// current sequence number for messages.
@@ -54,39 +105,73 @@ impl NetlinkSocket {
         unsafe { linux::read(self.fd, msg.as_mut_ptr(), msg.len()) }
     }
 
-    /// Drains the socket until a `nmsgerr` message is available. That message
-    /// is then read and depending on the error code inside of it, either a
-    /// Ok or Err is returned.
-    fn ack_error(&self) -> i32 {
+    /// Binds the socket, joining the rtnetlink multicast groups in `groups` (a bitmask, e.g.
+    /// `1 << (libc::RTNLGRP_LINK - 1)`) so that the kernel also delivers unsolicited
+    /// notifications to it, in addition to replies to requests sent on it.
+    fn bind(&self, groups: u32) -> i32 {
+        let addr = sockaddr_nl {
+            nl_family: u16::try_from(linux::AF_NETLINK).unwrap(),
+            nl_pad: 0,
+            nl_pid: 0,
+            nl_groups: groups,
+        };
+        unsafe {
+            linux::bind(
+                self.fd,
+                &addr as *const sockaddr_nl as *const u8,
+                u32::try_from(mem::size_of::<sockaddr_nl>()).unwrap(),
+            )
+        }
+    }
+
+    /// Receives and parses a response, which may span several datagrams: iterates every
+    /// `nlmsghdr` record found, stopping at `NLMSG_DONE` and surfacing `NLMSG_ERROR` as `Err`
+    /// (or `Ok(())` for an ack, i.e. an `NLMSG_ERROR` with error code `0`). Every other message is
+    /// passed to `on_message` as its type plus the bytes following the `nlmsghdr`, i.e. that
+    /// message's fixed-size header followed by its `rtattr` TLVs, which callers walk with
+    /// [`for_each_rtattr`].
+    fn receive_dump(&self, mut on_message: impl FnMut(i32, &[u8])) -> Result<(), i32> {
         loop {
             let mut buf = [0u8; 8192];
             let len = self.recv(&mut buf);
             if len < 0 {
-                return len.try_into().unwrap();
+                return Err(len.try_into().unwrap());
             }
             let len = usize::try_from(len).unwrap();
 
             let mut i = 0;
-            loop {
-                if i + mem::size_of::<libc::nlmsghdr>() > len {
-                    break;
-                }
+            while i + mem::size_of::<libc::nlmsghdr>() <= len {
                 let hdr =
                     unsafe { ptr::read_unaligned(buf[i..].as_ptr() as *const libc::nlmsghdr) };
-                if i32::from(hdr.nlmsg_type) == libc::NLMSG_ERROR {
-                    let payload = unsafe {
-                        ptr::read(buf[i + mem::size_of::<libc::nlmsghdr>()..].as_ptr()
-                            as *const libc::nlmsgerr)
-                    };
-                    return match payload.error {
-                        0 => 0,
-                        err => err,
-                    };
+                let msg_len = usize::try_from(hdr.nlmsg_len).unwrap();
+                let payload_off = i + mem::size_of::<libc::nlmsghdr>();
+                match i32::from(hdr.nlmsg_type) {
+                    libc::NLMSG_DONE => return Ok(()),
+                    libc::NLMSG_ERROR => {
+                        let payload = unsafe {
+                            ptr::read(buf[payload_off..].as_ptr() as *const libc::nlmsgerr)
+                        };
+                        return match payload.error {
+                            0 => Ok(()),
+                            err => Err(err),
+                        };
+                    }
+                    msg_type => on_message(msg_type, &buf[payload_off..i + msg_len]),
                 }
-                i += usize::try_from(hdr.nlmsg_len).unwrap();
+                i += msg_len;
             }
         }
     }
+
+    /// Drains the socket until a `nlmsgerr` (or `NLMSG_DONE`) message is available, discarding
+    /// any other message seen along the way. Returns `0` for an ack or `NLMSG_DONE`, or the
+    /// negative error code otherwise.
+    fn ack_error(&self) -> i32 {
+        match self.receive_dump(|_, _| {}) {
+            Ok(()) => 0,
+            Err(err) => err,
+        }
+    }
 }
 
 impl Drop for NetlinkSocket {
@@ -132,51 +217,121 @@ impl<T> RtAttr<T> {
 }
 
 #[repr(C)]
-struct AddAddrRequest {
+struct AddAddrRequestV4 {
+    hdr: libc::nlmsghdr,
+    payload: ifaddrmsg,
+    local: RtAttr<[u8; 4]>,
+    addr: RtAttr<[u8; 4]>,
+    broadcast: RtAttr<[u8; 4]>,
+}
+
+/// IPv6 has no concept of a broadcast address, so unlike [`AddAddrRequestV4`], there is no
+/// `IFA_BROADCAST` attribute to set here.
+#[repr(C)]
+struct AddAddrRequestV6 {
     hdr: libc::nlmsghdr,
     payload: ifaddrmsg,
-    local: RtAttr<u32>,
-    addr: RtAttr<u32>,
-    broadcast: RtAttr<u32>,
+    local: RtAttr<[u8; 16]>,
+    addr: RtAttr<[u8; 16]>,
+}
+
+/// Derives the IPv4 broadcast address for `addr/prefix_len`, i.e. `addr | !netmask`.
+fn default_broadcast_v4(addr: [u8; 4], prefix_len: u8) -> [u8; 4] {
+    let addr = u32::from_be_bytes(addr);
+    let netmask = if prefix_len == 0 {
+        0
+    } else {
+        u32::MAX << (32 - u32::from(prefix_len))
+    };
+    (addr | !netmask).to_be_bytes()
 }
 
+/// `broadcast` is only meaningful (and only ever `Some`) for an IPv4 `addr`.
 fn add_addr_to_interface(
     socket: &mut NetlinkSocket,
     interface_index: u32,
-    addr: Ipv4Addr,
-    broadcast: Ipv4Addr,
+    prefix_len: u8,
+    addr: IpAddr,
+    broadcast: Option<IpAddr>,
 ) -> i32 {
-    let req = AddAddrRequest {
-        hdr: libc::nlmsghdr {
-            nlmsg_len: u32::try_from(mem::size_of::<AddAddrRequest>()).unwrap(),
-            nlmsg_type: libc::RTM_NEWADDR,
-            nlmsg_flags: u16::try_from(
-                libc::NLM_F_REQUEST | libc::NLM_F_CREATE | libc::NLM_F_EXCL | libc::NLM_F_ACK,
-            )
-            .unwrap(),
-            nlmsg_seq: socket.next_seq(),
-            nlmsg_pid: 0,
-        },
-        payload: ifaddrmsg {
-            ifa_family: u8::try_from(libc::AF_INET).unwrap(),
-            ifa_prefixlen: 24,
-            ifa_flags: 0,
-            ifa_scope: 0,
-            ifa_index: interface_index,
-        },
-        local: RtAttr::new(libc::IFA_LOCAL, addr.to_be()),
-        addr: RtAttr::new(libc::IFA_ADDRESS, addr.to_be()),
-        broadcast: RtAttr::new(libc::IFA_BROADCAST, broadcast.to_be()),
-    };
-    let req_bytes = unsafe {
-        slice::from_raw_parts(
-            (&req as *const AddAddrRequest) as *const u8,
-            mem::size_of::<AddAddrRequest>(),
-        )
-    };
-    let ret = socket.send(req_bytes);
-    if ret < 0 {
-        return ret.try_into().unwrap();
+    match addr {
+        IpAddr::V4(addr) => {
+            let broadcast = match broadcast {
+                Some(IpAddr::V4(broadcast)) => broadcast,
+                _ => default_broadcast_v4(addr, prefix_len),
+            };
+            let req = AddAddrRequestV4 {
+                hdr: libc::nlmsghdr {
+                    nlmsg_len: u32::try_from(mem::size_of::<AddAddrRequestV4>()).unwrap(),
+                    nlmsg_type: libc::RTM_NEWADDR,
+                    nlmsg_flags: u16::try_from(
+                        libc::NLM_F_REQUEST
+                            | libc::NLM_F_CREATE
+                            | libc::NLM_F_EXCL
+                            | libc::NLM_F_ACK,
+                    )
+                    .unwrap(),
+                    nlmsg_seq: socket.next_seq(),
+                    nlmsg_pid: 0,
+                },
+                payload: ifaddrmsg {
+                    ifa_family: u8::try_from(libc::AF_INET).unwrap(),
+                    ifa_prefixlen: prefix_len,
+                    ifa_flags: 0,
+                    ifa_scope: 0,
+                    ifa_index: interface_index,
+                },
+                local: RtAttr::new(libc::IFA_LOCAL, addr),
+                addr: RtAttr::new(libc::IFA_ADDRESS, addr),
+                broadcast: RtAttr::new(libc::IFA_BROADCAST, broadcast),
+            };
+            let req_bytes = unsafe {
+                slice::from_raw_parts(
+                    (&req as *const AddAddrRequestV4) as *const u8,
+                    mem::size_of::<AddAddrRequestV4>(),
+                )
+            };
+            let ret = socket.send(req_bytes);
+            if ret < 0 {
+                return ret.try_into().unwrap();
+            }
+        }
+        IpAddr::V6(addr) => {
+            let req = AddAddrRequestV6 {
+                hdr: libc::nlmsghdr {
+                    nlmsg_len: u32::try_from(mem::size_of::<AddAddrRequestV6>()).unwrap(),
+                    nlmsg_type: libc::RTM_NEWADDR,
+                    nlmsg_flags: u16::try_from(
+                        libc::NLM_F_REQUEST
+                            | libc::NLM_F_CREATE
+                            | libc::NLM_F_EXCL
+                            | libc::NLM_F_ACK,
+                    )
+                    .unwrap(),
+                    nlmsg_seq: socket.next_seq(),
+                    nlmsg_pid: 0,
+                },
+                payload: ifaddrmsg {
+                    ifa_family: u8::try_from(libc::AF_INET6).unwrap(),
+                    ifa_prefixlen: prefix_len,
+                    ifa_flags: 0,
+                    ifa_scope: 0,
+                    ifa_index: interface_index,
+                },
+                local: RtAttr::new(libc::IFA_LOCAL, addr),
+                addr: RtAttr::new(libc::IFA_ADDRESS, addr),
+            };
+            let req_bytes = unsafe {
+                slice::from_raw_parts(
+                    (&req as *const AddAddrRequestV6) as *const u8,
+                    mem::size_of::<AddAddrRequestV6>(),
+                )
+            };
+            let ret = socket.send(req_bytes);
+            if ret < 0 {
+                return ret.try_into().unwrap();
+            }
+        }
     }
     socket.ack_error()
 }
@@ -195,52 +350,96 @@ struct rtmsg {
 }
 
 #[repr(C)]
-struct AddRouteRequest {
+struct AddRouteRequestV4 {
     hdr: libc::nlmsghdr,
     payload: rtmsg,
-    gateway: RtAttr<u32>,
+    gateway: RtAttr<[u8; 4]>,
     interface: RtAttr<u32>,
 }
 
-fn add_route_to_interface(
-    socket: &mut NetlinkSocket,
-    interface_index: u32,
-    gateway: Ipv4Addr,
-) -> i32 {
-    let req = AddRouteRequest {
-        hdr: libc::nlmsghdr {
-            nlmsg_len: u32::try_from(mem::size_of::<AddRouteRequest>()).unwrap(),
-            nlmsg_type: libc::RTM_NEWROUTE,
-            nlmsg_flags: u16::try_from(
-                libc::NLM_F_REQUEST | libc::NLM_F_CREATE | libc::NLM_F_EXCL | libc::NLM_F_ACK,
-            )
-            .unwrap(),
-            nlmsg_seq: socket.next_seq(),
-            nlmsg_pid: 0,
-        },
-        payload: rtmsg {
-            rtm_family: u8::try_from(libc::AF_INET).unwrap(),
-            rtm_dst_len: 0,
-            rtm_src_len: 0,
-            rtm_tos: 0,
-            rtm_table: libc::RT_TABLE_MAIN,
-            rtm_protocol: libc::RTPROT_BOOT,
-            rtm_scope: libc::RT_SCOPE_UNIVERSE,
-            rtm_type: libc::RTN_UNICAST,
-            rtm_flags: 0,
-        },
-        gateway: RtAttr::new(libc::RTA_GATEWAY, gateway.to_be()),
-        interface: RtAttr::new(libc::RTA_OIF, interface_index),
-    };
-    let req_bytes = unsafe {
-        slice::from_raw_parts(
-            (&req as *const AddRouteRequest) as *const u8,
-            mem::size_of::<AddRouteRequest>(),
-        )
+#[repr(C)]
+struct AddRouteRequestV6 {
+    hdr: libc::nlmsghdr,
+    payload: rtmsg,
+    gateway: RtAttr<[u8; 16]>,
+    interface: RtAttr<u32>,
+}
+
+/// Adds a default route (`rtm_dst_len` stays `0`) through `gateway`, which may be the link-local
+/// or global next hop for an IPv6 gateway.
+fn add_route_to_interface(socket: &mut NetlinkSocket, interface_index: u32, gateway: IpAddr) -> i32 {
+    let payload = |family| rtmsg {
+        rtm_family: family,
+        rtm_dst_len: 0,
+        rtm_src_len: 0,
+        rtm_tos: 0,
+        rtm_table: libc::RT_TABLE_MAIN,
+        rtm_protocol: libc::RTPROT_BOOT,
+        rtm_scope: libc::RT_SCOPE_UNIVERSE,
+        rtm_type: libc::RTN_UNICAST,
+        rtm_flags: 0,
     };
-    let ret = socket.send(req_bytes);
-    if ret < 0 {
-        return ret.try_into().unwrap();
+    match gateway {
+        IpAddr::V4(gateway) => {
+            let req = AddRouteRequestV4 {
+                hdr: libc::nlmsghdr {
+                    nlmsg_len: u32::try_from(mem::size_of::<AddRouteRequestV4>()).unwrap(),
+                    nlmsg_type: libc::RTM_NEWROUTE,
+                    nlmsg_flags: u16::try_from(
+                        libc::NLM_F_REQUEST
+                            | libc::NLM_F_CREATE
+                            | libc::NLM_F_EXCL
+                            | libc::NLM_F_ACK,
+                    )
+                    .unwrap(),
+                    nlmsg_seq: socket.next_seq(),
+                    nlmsg_pid: 0,
+                },
+                payload: payload(u8::try_from(libc::AF_INET).unwrap()),
+                gateway: RtAttr::new(libc::RTA_GATEWAY, gateway),
+                interface: RtAttr::new(libc::RTA_OIF, interface_index),
+            };
+            let req_bytes = unsafe {
+                slice::from_raw_parts(
+                    (&req as *const AddRouteRequestV4) as *const u8,
+                    mem::size_of::<AddRouteRequestV4>(),
+                )
+            };
+            let ret = socket.send(req_bytes);
+            if ret < 0 {
+                return ret.try_into().unwrap();
+            }
+        }
+        IpAddr::V6(gateway) => {
+            let req = AddRouteRequestV6 {
+                hdr: libc::nlmsghdr {
+                    nlmsg_len: u32::try_from(mem::size_of::<AddRouteRequestV6>()).unwrap(),
+                    nlmsg_type: libc::RTM_NEWROUTE,
+                    nlmsg_flags: u16::try_from(
+                        libc::NLM_F_REQUEST
+                            | libc::NLM_F_CREATE
+                            | libc::NLM_F_EXCL
+                            | libc::NLM_F_ACK,
+                    )
+                    .unwrap(),
+                    nlmsg_seq: socket.next_seq(),
+                    nlmsg_pid: 0,
+                },
+                payload: payload(u8::try_from(libc::AF_INET6).unwrap()),
+                gateway: RtAttr::new(libc::RTA_GATEWAY, gateway),
+                interface: RtAttr::new(libc::RTA_OIF, interface_index),
+            };
+            let req_bytes = unsafe {
+                slice::from_raw_parts(
+                    (&req as *const AddRouteRequestV6) as *const u8,
+                    mem::size_of::<AddRouteRequestV6>(),
+                )
+            };
+            let ret = socket.send(req_bytes);
+            if ret < 0 {
+                return ret.try_into().unwrap();
+            }
+        }
     }
     socket.ack_error()
 }
@@ -291,39 +490,541 @@ fn bring_interface_admin_up(socket: &mut NetlinkSocket, interface_index: i32) ->
     socket.ack_error()
 }
 
+/// Returns the current value of `CLOCK_MONOTONIC` in milliseconds.
+pub(crate) fn monotonic_ms() -> i64 {
+    let mut ts = core::mem::MaybeUninit::<linux::timespec>::uninit();
+    unsafe { linux::clock_gettime(linux::CLOCK_MONOTONIC, ts.as_mut_ptr()) };
+    let ts = unsafe { ts.assume_init() };
+    ts.tv_sec * 1000 + ts.tv_nsec / 1_000_000
+}
+
+/// Blocks until `interface_index` reports carrier (`IFF_RUNNING` and `IFF_LOWER_UP` both set in
+/// an `RTM_NEWLINK` notification) or `timeout_ms` elapses, in which case `-libc::ETIMEDOUT` is
+/// returned. Useful between [`bring_interface_admin_up`] and [`add_route_to_interface`], since a
+/// route added before the link has carrier (common with slow PHY negotiation or virtio hotplug)
+/// can otherwise fail.
+fn wait_for_carrier(interface_index: u32, timeout_ms: i32) -> i32 {
+    let mut socket = match NetlinkSocket::new(libc::NETLINK_ROUTE) {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    // Join the multicast group before checking the current state, not after: otherwise a carrier
+    // transition landing between the check and the `bind()` would be missed entirely.
+    let ret = socket.bind(1 << (libc::RTNLGRP_LINK - 1));
+    if ret < 0 {
+        return ret;
+    }
+    match socket.link_has_carrier(interface_index) {
+        Ok(true) => return 0,
+        Ok(false) => {}
+        Err(e) => return e,
+    }
+
+    let epfd = linux::epoll_create1(linux::EPOLL_CLOEXEC);
+    if epfd < 0 {
+        return epfd;
+    }
+    let epfd = linux::Fd(epfd.try_into().unwrap());
+    let mut event = linux::epoll_event {
+        events: linux::EPOLLIN,
+        data: socket.fd.into(),
+    };
+    let ret = unsafe {
+        linux::epoll_ctl(
+            epfd.0,
+            linux::EPOLL_CTL_ADD,
+            socket.fd,
+            &mut event as *mut _,
+        )
+    };
+    if ret < 0 {
+        return ret;
+    }
+
+    let carrier_flags =
+        u32::try_from(libc::IFF_RUNNING).unwrap() | u32::try_from(libc::IFF_LOWER_UP).unwrap();
+    let deadline = monotonic_ms() + i64::from(timeout_ms);
+    loop {
+        let remaining = deadline - monotonic_ms();
+        if remaining <= 0 {
+            return -libc::ETIMEDOUT;
+        }
+        let mut events = [linux::epoll_event { events: 0, data: 0 }; 1];
+        let n = linux::epoll_wait(epfd.0, &mut events, i32::try_from(remaining).unwrap());
+        if n < 0 {
+            return n;
+        }
+        if n == 0 {
+            return -libc::ETIMEDOUT;
+        }
+
+        let mut buf = [0u8; 8192];
+        let len = socket.recv(&mut buf);
+        if len < 0 {
+            return len.try_into().unwrap();
+        }
+        let len = usize::try_from(len).unwrap();
+
+        let mut i = 0;
+        while i + mem::size_of::<libc::nlmsghdr>() <= len {
+            let hdr = unsafe { ptr::read_unaligned(buf[i..].as_ptr() as *const libc::nlmsghdr) };
+            let msg_len = usize::try_from(hdr.nlmsg_len).unwrap();
+            let payload_off = i + mem::size_of::<libc::nlmsghdr>();
+            if i32::from(hdr.nlmsg_type) == libc::RTM_NEWLINK
+                && payload_off + mem::size_of::<ifinfomsg>() <= i + msg_len
+            {
+                let link =
+                    unsafe { ptr::read_unaligned(buf[payload_off..].as_ptr() as *const ifinfomsg) };
+                if u32::try_from(link.ifi_index).unwrap_or(0) == interface_index
+                    && link.ifi_flags & carrier_flags == carrier_flags
+                {
+                    return 0;
+                }
+            }
+            i += msg_len;
+        }
+    }
+}
+
+/// Rounds `len` up to the rtattr alignment boundary, as `RTA_ALIGN()` does in the kernel headers.
+fn rta_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Calls `f` with the `rta_type` and value bytes of every rtattr in `attrs`, which must start
+/// right after a netlink message's fixed-size payload (e.g. just past an `ifinfomsg`).
+fn for_each_rtattr(mut attrs: &[u8], mut f: impl FnMut(u16, &[u8])) {
+    while attrs.len() >= mem::size_of::<rtattr>() {
+        let hdr = unsafe { ptr::read_unaligned(attrs.as_ptr() as *const rtattr) };
+        let len = usize::from(hdr.rta_len);
+        if len < mem::size_of::<rtattr>() || len > attrs.len() {
+            break;
+        }
+        f(hdr.rta_type, &attrs[mem::size_of::<rtattr>()..len]);
+        attrs = attrs.get(rta_align(len)..).unwrap_or(&[]);
+    }
+}
+
+#[repr(C)]
+struct GetLinkRequest {
+    hdr: libc::nlmsghdr,
+    payload: ifinfomsg,
+}
+
+#[repr(C)]
+struct GetAddrRequest {
+    hdr: libc::nlmsghdr,
+    payload: ifaddrmsg,
+}
+
+impl NetlinkSocket {
+    /// Resolves a network interface name (e.g. `b"eth0"`, without a trailing NUL) to its
+    /// `ifi_index` by dumping every link via `RTM_GETLINK` and matching the `IFLA_IFNAME`
+    /// attribute of each against `name`.
+    fn resolve_interface_index(&mut self, name: &[u8]) -> Result<u32, i32> {
+        let req = GetLinkRequest {
+            hdr: libc::nlmsghdr {
+                nlmsg_len: u32::try_from(mem::size_of::<GetLinkRequest>()).unwrap(),
+                nlmsg_type: libc::RTM_GETLINK,
+                nlmsg_flags: u16::try_from(libc::NLM_F_REQUEST | libc::NLM_F_DUMP).unwrap(),
+                nlmsg_seq: self.next_seq(),
+                nlmsg_pid: 0,
+            },
+            payload: ifinfomsg {
+                ifi_family: u8::try_from(libc::AF_UNSPEC).unwrap(),
+                ifi_type: 0,
+                ifi_index: 0,
+                ifi_flags: 0,
+                ifi_change: 0,
+            },
+        };
+        let req_bytes = unsafe {
+            slice::from_raw_parts(
+                (&req as *const GetLinkRequest) as *const u8,
+                mem::size_of::<GetLinkRequest>(),
+            )
+        };
+        let ret = self.send(req_bytes);
+        if ret < 0 {
+            return Err(ret.try_into().unwrap());
+        }
+
+        let mut index = None;
+        self.receive_dump(|msg_type, payload| {
+            if index.is_some() || msg_type != libc::RTM_NEWLINK {
+                return;
+            }
+            if payload.len() < mem::size_of::<ifinfomsg>() {
+                return;
+            }
+            let link = unsafe { ptr::read_unaligned(payload.as_ptr() as *const ifinfomsg) };
+            let mut found = false;
+            for_each_rtattr(&payload[mem::size_of::<ifinfomsg>()..], |ty, val| {
+                if ty == libc::IFLA_IFNAME && val.strip_suffix(b"\0").unwrap_or(val) == name {
+                    found = true;
+                }
+            });
+            if found {
+                index = Some(u32::try_from(link.ifi_index).unwrap());
+            }
+        })?;
+        index.ok_or(-libc::ENODEV)
+    }
+
+    /// Dumps every configured address via `RTM_GETADDR` and returns whether `addr` is already
+    /// assigned to `interface_index`, so that callers can skip the `NLM_F_EXCL` failure an
+    /// `RTM_NEWADDR` for an already-present address would otherwise hit.
+    fn has_address(&mut self, interface_index: u32, addr: IpAddr) -> Result<bool, i32> {
+        let req = GetAddrRequest {
+            hdr: libc::nlmsghdr {
+                nlmsg_len: u32::try_from(mem::size_of::<GetAddrRequest>()).unwrap(),
+                nlmsg_type: libc::RTM_GETADDR,
+                nlmsg_flags: u16::try_from(libc::NLM_F_REQUEST | libc::NLM_F_DUMP).unwrap(),
+                nlmsg_seq: self.next_seq(),
+                nlmsg_pid: 0,
+            },
+            payload: ifaddrmsg {
+                ifa_family: u8::try_from(libc::AF_UNSPEC).unwrap(),
+                ifa_prefixlen: 0,
+                ifa_flags: 0,
+                ifa_scope: 0,
+                ifa_index: 0,
+            },
+        };
+        let req_bytes = unsafe {
+            slice::from_raw_parts(
+                (&req as *const GetAddrRequest) as *const u8,
+                mem::size_of::<GetAddrRequest>(),
+            )
+        };
+        let ret = self.send(req_bytes);
+        if ret < 0 {
+            return Err(ret.try_into().unwrap());
+        }
+
+        let mut found = false;
+        self.receive_dump(|msg_type, payload| {
+            if found || msg_type != libc::RTM_NEWADDR {
+                return;
+            }
+            if payload.len() < mem::size_of::<ifaddrmsg>() {
+                return;
+            }
+            let msg = unsafe { ptr::read_unaligned(payload.as_ptr() as *const ifaddrmsg) };
+            if msg.ifa_index != interface_index {
+                return;
+            }
+            for_each_rtattr(&payload[mem::size_of::<ifaddrmsg>()..], |ty, val| {
+                if ty != libc::IFA_ADDRESS {
+                    return;
+                }
+                let matches = match addr {
+                    IpAddr::V4(a) => val == &a[..],
+                    IpAddr::V6(a) => val == &a[..],
+                };
+                if matches {
+                    found = true;
+                }
+            });
+        })?;
+        Ok(found)
+    }
+
+    /// Dumps `RTM_GETLINK` and returns the link-layer (MAC) address of `interface_index`, e.g.
+    /// for use as the `chaddr` of a DHCP request.
+    fn interface_mac(&mut self, interface_index: u32) -> Result<[u8; 6], i32> {
+        let req = GetLinkRequest {
+            hdr: libc::nlmsghdr {
+                nlmsg_len: u32::try_from(mem::size_of::<GetLinkRequest>()).unwrap(),
+                nlmsg_type: libc::RTM_GETLINK,
+                nlmsg_flags: u16::try_from(libc::NLM_F_REQUEST | libc::NLM_F_DUMP).unwrap(),
+                nlmsg_seq: self.next_seq(),
+                nlmsg_pid: 0,
+            },
+            payload: ifinfomsg {
+                ifi_family: u8::try_from(libc::AF_UNSPEC).unwrap(),
+                ifi_type: 0,
+                ifi_index: 0,
+                ifi_flags: 0,
+                ifi_change: 0,
+            },
+        };
+        let req_bytes = unsafe {
+            slice::from_raw_parts(
+                (&req as *const GetLinkRequest) as *const u8,
+                mem::size_of::<GetLinkRequest>(),
+            )
+        };
+        let ret = self.send(req_bytes);
+        if ret < 0 {
+            return Err(ret.try_into().unwrap());
+        }
+
+        let mut mac = None;
+        self.receive_dump(|msg_type, payload| {
+            if mac.is_some() || msg_type != libc::RTM_NEWLINK {
+                return;
+            }
+            if payload.len() < mem::size_of::<ifinfomsg>() {
+                return;
+            }
+            let link = unsafe { ptr::read_unaligned(payload.as_ptr() as *const ifinfomsg) };
+            if u32::try_from(link.ifi_index).unwrap() != interface_index {
+                return;
+            }
+            for_each_rtattr(&payload[mem::size_of::<ifinfomsg>()..], |ty, val| {
+                if ty == libc::IFLA_ADDRESS && val.len() == 6 {
+                    let mut addr = [0u8; 6];
+                    addr.copy_from_slice(val);
+                    mac = Some(addr);
+                }
+            });
+        })?;
+        mac.ok_or(-libc::ENODEV)
+    }
+
+    /// Dumps `RTM_GETLINK` and returns whether `interface_index` already has carrier (`IFF_RUNNING`
+    /// and `IFF_LOWER_UP` both set), e.g. because it negotiated during kernel init (common for
+    /// virtio, loopback, or a cable that was already plugged in). Lets [`wait_for_carrier`] skip
+    /// its wait entirely instead of blocking for a notification that will never come.
+    fn link_has_carrier(&mut self, interface_index: u32) -> Result<bool, i32> {
+        let req = GetLinkRequest {
+            hdr: libc::nlmsghdr {
+                nlmsg_len: u32::try_from(mem::size_of::<GetLinkRequest>()).unwrap(),
+                nlmsg_type: libc::RTM_GETLINK,
+                nlmsg_flags: u16::try_from(libc::NLM_F_REQUEST | libc::NLM_F_DUMP).unwrap(),
+                nlmsg_seq: self.next_seq(),
+                nlmsg_pid: 0,
+            },
+            payload: ifinfomsg {
+                ifi_family: u8::try_from(libc::AF_UNSPEC).unwrap(),
+                ifi_type: 0,
+                ifi_index: 0,
+                ifi_flags: 0,
+                ifi_change: 0,
+            },
+        };
+        let req_bytes = unsafe {
+            slice::from_raw_parts(
+                (&req as *const GetLinkRequest) as *const u8,
+                mem::size_of::<GetLinkRequest>(),
+            )
+        };
+        let ret = self.send(req_bytes);
+        if ret < 0 {
+            return Err(ret.try_into().unwrap());
+        }
+
+        let carrier_flags =
+            u32::try_from(libc::IFF_RUNNING).unwrap() | u32::try_from(libc::IFF_LOWER_UP).unwrap();
+        let mut has_carrier = false;
+        self.receive_dump(|msg_type, payload| {
+            if has_carrier || msg_type != libc::RTM_NEWLINK {
+                return;
+            }
+            if payload.len() < mem::size_of::<ifinfomsg>() {
+                return;
+            }
+            let link = unsafe { ptr::read_unaligned(payload.as_ptr() as *const ifinfomsg) };
+            if u32::try_from(link.ifi_index).unwrap_or(0) == interface_index
+                && link.ifi_flags & carrier_flags == carrier_flags
+            {
+                has_carrier = true;
+            }
+        })?;
+        Ok(has_carrier)
+    }
+}
+
+/// Looks up the link-layer (MAC) address of `interface_index`, for use as the `chaddr` of a DHCP
+/// request.
+pub(crate) fn get_interface_mac(interface_index: u32) -> Result<[u8; 6], i32> {
+    let mut socket = match NetlinkSocket::new(libc::NETLINK_ROUTE) {
+        Ok(s) => s,
+        Err(e) => return Err(e),
+    };
+    socket.interface_mac(interface_index)
+}
+
+/// Writes a string to a fixed file descriptor via [`linux::write`], for use with `write!`/
+/// `writeln!`. Unlike [`linux::Stdout`]/[`linux::Stderr`], which always write to fd 1/2, the fd
+/// is chosen by the caller, who also owns it: it is not closed on drop.
+struct FdWriter(u32);
+
+impl fmt::Write for FdWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if linux::write(self.0, s.as_bytes()) < 0 {
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+/// Writes a `nameserver` line for every address in `config::DNS_SERVERS` to `/etc/resolv.conf`.
+fn write_resolv_conf() -> i32 {
+    let fd = unsafe {
+        linux::open(
+            b"/etc/resolv.conf\0" as *const u8,
+            linux::O_WRONLY | linux::O_CREAT | linux::O_TRUNC,
+            0o644,
+        )
+    };
+    let fd = match u32::try_from(fd) {
+        Ok(fd) => fd,
+        Err(_) => return fd,
+    };
+    let mut writer = FdWriter(fd);
+    for &server in config::DNS_SERVERS.iter() {
+        if writeln!(writer, "nameserver {server}").is_err() {
+            linux::close(fd);
+            return -libc::EIO;
+        }
+    }
+    linux::close(fd);
+    0
+}
+
+/// Writes a `127.0.0.1 <config::HOSTNAME> localhost` entry to `/etc/hosts`, so that the
+/// hostname resolves locally even without a DNS server configured.
+fn write_hosts() -> i32 {
+    let fd = unsafe {
+        linux::open(
+            b"/etc/hosts\0" as *const u8,
+            linux::O_WRONLY | linux::O_CREAT | linux::O_TRUNC,
+            0o644,
+        )
+    };
+    let fd = match u32::try_from(fd) {
+        Ok(fd) => fd,
+        Err(_) => return fd,
+    };
+    let mut writer = FdWriter(fd);
+    let ret = if writeln!(writer, "127.0.0.1 {} localhost", config::HOSTNAME).is_err() {
+        -libc::EIO
+    } else {
+        0
+    };
+    linux::close(fd);
+    ret
+}
+
 pub fn setup_networking() -> i32 {
     let mut socket = match NetlinkSocket::new(libc::NETLINK_ROUTE) {
         Ok(s) => s,
         Err(e) => return e,
     };
-    for interface in config::NET_INTERFACES.iter() {
+
+    // Resolved up front into a side array (rather than re-resolving inside each loop below)
+    // since a name lookup needs a dump round-trip through the kernel.
+    let mut indices = [0u32; config::NET_INTERFACES.len()];
+    for (i, interface) in config::NET_INTERFACES.iter().enumerate() {
+        indices[i] = match &interface.id {
+            config::NetInterfaceId::Index(index) => *index,
+            config::NetInterfaceId::Name { bytes, len } => {
+                match socket.resolve_interface_index(&bytes[..usize::from(*len)]) {
+                    Ok(index) => index,
+                    Err(e) => return e,
+                }
+            }
+        };
+    }
+
+    for (interface, &index) in config::NET_INTERFACES.iter().zip(indices.iter()) {
         let addr = match interface.addr {
             Some(val) => val,
             None => continue,
         };
-        let broadcast = interface
-            .broadcast
-            .unwrap_or_else(|| u32::from_be_bytes([255, 255, 255, 0]));
-        let ret = add_addr_to_interface(&mut socket, interface.index, addr, broadcast);
+        // IPv6 has no broadcast address; only look at the configured/default one for IPv4.
+        let broadcast = match (addr, interface.broadcast) {
+            (IpAddr::V4(_), Some(broadcast)) => Some(broadcast),
+            (IpAddr::V4(addr), None) => {
+                Some(IpAddr::V4(default_broadcast_v4(addr, interface.prefix_len)))
+            }
+            (IpAddr::V6(_), _) => None,
+        };
+        match socket.has_address(index, addr) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => return e,
+        }
+        let ret = add_addr_to_interface(&mut socket, index, interface.prefix_len, addr, broadcast);
         if ret < 0 {
             return ret;
         }
     }
-    for interface in config::NET_INTERFACES.iter() {
-        let ret = bring_interface_admin_up(&mut socket, i32::try_from(interface.index).unwrap());
+    for &index in indices.iter() {
+        let ret = bring_interface_admin_up(&mut socket, i32::try_from(index).unwrap());
         if ret < 0 {
             return ret;
         }
     }
-    for interface in config::NET_INTERFACES.iter() {
+    for (interface, &index) in config::NET_INTERFACES.iter().zip(indices.iter()) {
+        if !interface.dhcp {
+            continue;
+        }
+        let lease = match dhcp::obtain_lease(index) {
+            Ok(lease) => lease,
+            Err(e) if e == -libc::ETIMEDOUT => {
+                // No DHCP server answered in time: leave this interface unconfigured rather than
+                // aborting the rest of setup_networking (other interfaces, resolv.conf, /etc/hosts)
+                // over it.
+                writeln!(
+                    linux::Stderr,
+                    "timed out waiting for a DHCP lease on interface index {index}"
+                )
+                .unwrap();
+                continue;
+            }
+            Err(e) => return e,
+        };
+        let addr = IpAddr::V4(lease.addr);
+        match socket.has_address(index, addr) {
+            Ok(true) => {}
+            Ok(false) => {
+                let ret = add_addr_to_interface(&mut socket, index, lease.prefix_len, addr, None);
+                if ret < 0 {
+                    return ret;
+                }
+            }
+            Err(e) => return e,
+        }
+        if let Some(router) = lease.router {
+            let ret = wait_for_carrier(index, CARRIER_TIMEOUT_MS);
+            if ret == -libc::ETIMEDOUT {
+                // No carrier yet: leave the route for this interface unset rather than aborting
+                // the rest of setup_networking (other interfaces, resolv.conf, /etc/hosts) over it.
+                continue;
+            } else if ret < 0 {
+                return ret;
+            }
+            let ret = add_route_to_interface(&mut socket, index, IpAddr::V4(router));
+            if ret < 0 {
+                return ret;
+            }
+        }
+    }
+    for (interface, &index) in config::NET_INTERFACES.iter().zip(indices.iter()) {
         let gateway = match interface.gateway {
             Some(val) => val,
             None => continue,
         };
-        let ret = add_route_to_interface(&mut socket, interface.index, gateway);
+        let ret = wait_for_carrier(index, CARRIER_TIMEOUT_MS);
+        if ret == -libc::ETIMEDOUT {
+            // No carrier yet: leave the route for this interface unset rather than aborting the
+            // rest of setup_networking (other interfaces, resolv.conf, /etc/hosts) over it.
+            continue;
+        } else if ret < 0 {
+            return ret;
+        }
+        let ret = add_route_to_interface(&mut socket, index, gateway);
         if ret < 0 {
             return ret;
         }
     }
-    0
+
+    let ret = write_resolv_conf();
+    if ret < 0 {
+        return ret;
+    }
+    write_hosts()
 }
@@ -1,8 +1,15 @@
 //! This is a lighter implementation of something like udev and seatd. The goal is for for the
 //! Wayland compositor, and only it, be able to request access to GPU and input devices. To do
-//! that, it is given a FD to a UNIX socket on which it will send a datagram with the path to a
-//! device to that socket followed by a NUL byte, and it will receive a datagram with a FD of the
-//! device if the request was allowed, or an empty datagram otherwise.
+//! that, it is given a FD to a UNIX socket on which it sends a datagram: the first byte is a
+//! [`VERB_OPEN`]/[`VERB_PAUSE`]/[`VERB_RESUME`] verb, followed by the path to a device and a NUL
+//! byte. For `VERB_OPEN`, it will receive a datagram with a FD of the device if the request was
+//! allowed, or an empty datagram otherwise; the other verbs get no reply.
+//!
+//! Issued fds are tracked so that they can be paused and resumed as the seat's VT is switched
+//! away from and back to, the way logind/seatd do: [`SeatServer::new`] puts the tty in
+//! `VT_PROCESS` mode, so the kernel notifies us with a signal instead of just switching away, and
+//! [`SeatServer::process_vt_signal`] reacts to it by dropping DRM master on GPU fds and revoking
+//! input fds, then restoring DRM master once the VT is reacquired.
 
 use core::convert::{TryFrom, TryInto};
 use core::fmt::Write;
@@ -11,6 +18,21 @@ use core::ptr;
 
 use crate::linux::{self, Fd};
 
+/// Maximum number of fds handed out to the compositor at once that we track for pausing/resuming
+/// across VT switches. A handful of GPU nodes plus one fd per input device is far below this.
+const MAX_ISSUED_DEVICES: usize = 32;
+
+/// The device path to open for this seat's VT, so that we can `VT_SETMODE`/`VT_RELDISP` it.
+const VT_PATH: &[u8] = b"/dev/tty0\0";
+
+/// Requests a device fd; payload is the device path followed by a NUL byte. Replied to with a
+/// datagram carrying the fd, or an empty one on error.
+const VERB_OPEN: u8 = 0;
+/// Asks us to pause (drop DRM master / revoke) a previously issued device, identified by path.
+const VERB_PAUSE: u8 = 1;
+/// Asks us to resume (restore DRM master for) a previously issued device, identified by path.
+const VERB_RESUME: u8 = 2;
+
 #[repr(C)]
 struct RightsCtrlMsg {
     hdr: linux::cmsghdr,
@@ -30,10 +52,103 @@ impl RightsCtrlMsg {
     }
 }
 
-/// A seat server is an object to process device open requests from the Wayland compositor. It will
-/// receive those requests on a anonymous UNIX socket.
+/// Which kind of revocation a device fd needs when its VT is switched away from.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum DeviceKind {
+    /// A GPU node: pause with `DRM_IOCTL_DROP_MASTER`, resume with `DRM_IOCTL_SET_MASTER`.
+    Drm,
+    /// An input node: pause with `EVIOCREVOKE`, which cannot be undone.
+    Input,
+}
+
+/// A device class the compositor is allowed to request, written as a fixed `prefix` followed by
+/// nothing but ASCII digits (e.g. `/dev/dri/card0`). Requiring the suffix to be digits-only also
+/// rules out path traversal (`..`, extra `/`) without needing a `realpath`-style syscall.
+struct DeviceClass {
+    prefix: &'static [u8],
+    /// Whether the compositor may write to devices in this class. GPU nodes need `ioctl`s that
+    /// require `O_RDWR`; input nodes only ever need to be read from.
+    writable: bool,
+    kind: DeviceKind,
+}
+
+/// The only devices the compositor is allowed to open: GPU and input nodes.
+const ALLOWED_DEVICE_CLASSES: &[DeviceClass] = &[
+    DeviceClass {
+        prefix: b"/dev/dri/card",
+        writable: true,
+        kind: DeviceKind::Drm,
+    },
+    DeviceClass {
+        prefix: b"/dev/dri/renderD",
+        writable: true,
+        kind: DeviceKind::Drm,
+    },
+    DeviceClass {
+        prefix: b"/dev/input/event",
+        writable: false,
+        kind: DeviceKind::Input,
+    },
+];
+
+/// Returns the `open` flags and [`DeviceKind`] to use for `path` (a NUL-terminated device path)
+/// if it matches one of [`ALLOWED_DEVICE_CLASSES`], or `None` if the compositor must not be
+/// allowed to open it.
+fn classify_device(path: &[u8]) -> Option<(u32, DeviceKind)> {
+    // Exclude the NUL terminator from the match.
+    let path = &path[..path.len() - 1];
+    for class in ALLOWED_DEVICE_CLASSES {
+        if let Some(suffix) = path.strip_prefix(class.prefix) {
+            if !suffix.is_empty() && suffix.iter().all(u8::is_ascii_digit) {
+                let access = if class.writable {
+                    linux::O_RDWR
+                } else {
+                    linux::O_RDONLY
+                };
+                return Some((
+                    access
+                        | linux::O_NOCTTY
+                        | linux::O_NOFOLLOW
+                        | linux::O_CLOEXEC
+                        | linux::O_NONBLOCK,
+                    class.kind,
+                ));
+            }
+        }
+    }
+    None
+}
+
+/// A device fd handed out to the compositor, tracked so that it can be paused/resumed across VT
+/// switches. The path is kept so `VERB_PAUSE`/`VERB_RESUME` requests can look a device back up by
+/// path; a device whose path does not fit is not tracked at all (every path accepted by
+/// [`classify_device`] is far shorter than this).
+struct IssuedDevice {
+    /// Kept open for the lifetime of the entry: `ioctl`s on this fd affect the same underlying
+    /// `struct file` as the copy we sent the compositor over `SCM_RIGHTS`, which is the whole
+    /// point of dropping/setting DRM master or revoking from here.
+    fd: Fd,
+    kind: DeviceKind,
+    /// Set once we have dropped master/revoked this device for the current VT switch-away.
+    paused: bool,
+    path: [u8; 40],
+    path_len: u8,
+}
+
+impl IssuedDevice {
+    fn path(&self) -> &[u8] {
+        &self.path[..self.path_len as usize]
+    }
+}
+
+/// A seat server is an object to process device open requests from the Wayland compositor. It
+/// will receive those requests on a anonymous UNIX socket, and also owns the VT this seat runs
+/// on so it can pause/resume issued devices as it is switched away from and back to.
 pub struct SeatServer {
     fd: Fd,
+    vt_fd: Fd,
+    vt_signal_fd: Fd,
+    issued: [Option<IssuedDevice>; MAX_ISSUED_DEVICES],
 }
 
 impl SeatServer {
@@ -55,7 +170,262 @@ impl SeatServer {
             return Err(ret);
         }
 
-        Ok((Self { fd: pair.0 }, pair.1))
+        let vt_fd = unsafe {
+            linux::open(
+                VT_PATH.as_ptr(),
+                linux::O_RDWR | linux::O_NOCTTY | linux::O_CLOEXEC,
+                0,
+            )
+        };
+        if vt_fd < 0 {
+            return Err(vt_fd);
+        }
+        let vt_fd = Fd(u32::try_from(vt_fd).unwrap());
+
+        // Block the release/acquire signals before creating the signalfd, same as with `SIGCHLD`
+        // in `main::run_event_loop`: otherwise the default disposition (terminate, for both of
+        // these) would act on a delivery instead of leaving it pending for the signalfd.
+        let vt_signal_mask = linux::sigset_t::empty()
+            .with(linux::SIGUSR1)
+            .with(linux::SIGUSR2);
+        let ret = unsafe { linux::rt_sigprocmask(linux::SIG_BLOCK, &vt_signal_mask) };
+        if ret < 0 {
+            return Err(ret);
+        }
+        let vt_signal_fd = linux::signalfd4(
+            -1,
+            &vt_signal_mask,
+            linux::SFD_NONBLOCK | linux::SFD_CLOEXEC,
+        );
+        if vt_signal_fd < 0 {
+            return Err(vt_signal_fd);
+        }
+        let vt_signal_fd = Fd(u32::try_from(vt_signal_fd).unwrap());
+
+        // Ask the kernel to notify us with `SIGUSR1`/`SIGUSR2` instead of switching the VT away
+        // immediately, so that we get a chance to pause issued devices first.
+        let mode = linux::vt_mode {
+            mode: linux::VT_PROCESS,
+            waitv: 0,
+            relsig: linux::SIGUSR1 as i16,
+            acqsig: linux::SIGUSR2 as i16,
+            frsig: 0,
+        };
+        let ret = unsafe { linux::ioctl(vt_fd.0, linux::VT_SETMODE, &mode as *const _ as u64) };
+        if ret < 0 {
+            return Err(ret);
+        }
+
+        Ok((
+            Self {
+                fd: pair.0,
+                vt_fd,
+                vt_signal_fd,
+                issued: [(); MAX_ISSUED_DEVICES].map(|()| None),
+            },
+            pair.1,
+        ))
+    }
+
+    fn find_issued(&self, path: &[u8]) -> Option<usize> {
+        self.issued
+            .iter()
+            .position(|slot| matches!(slot, Some(dev) if dev.path() == path))
+    }
+
+    /// Drops DRM master / revokes every issued device that isn't already paused, because the
+    /// seat's VT is being switched away from.
+    fn pause_all(&mut self) {
+        for slot in &mut self.issued {
+            let dev = match slot {
+                Some(dev) if !dev.paused => dev,
+                _ => continue,
+            };
+            match dev.kind {
+                DeviceKind::Drm => {
+                    let ret = unsafe { linux::ioctl(dev.fd.0, linux::DRM_IOCTL_DROP_MASTER, 0) };
+                    if ret < 0 {
+                        writeln!(linux::Stderr, "failed to drop DRM master: {ret}").unwrap();
+                    }
+                    dev.paused = true;
+                }
+                DeviceKind::Input => {
+                    let ret = unsafe { linux::ioctl(dev.fd.0, linux::EVIOCREVOKE, 0) };
+                    if ret < 0 {
+                        writeln!(linux::Stderr, "failed to revoke input device: {ret}").unwrap();
+                    }
+                    // `EVIOCREVOKE` cannot be undone: the compositor has to close and re-request
+                    // the device once the seat is reacquired, so there is nothing left to track.
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    /// Restores DRM master on every paused GPU device, because the seat's VT was reacquired.
+    fn resume_all(&mut self) {
+        for dev in self.issued.iter_mut().flatten() {
+            if dev.kind == DeviceKind::Drm && dev.paused {
+                let ret = unsafe { linux::ioctl(dev.fd.0, linux::DRM_IOCTL_SET_MASTER, 0) };
+                if ret < 0 {
+                    writeln!(linux::Stderr, "failed to set DRM master: {ret}").unwrap();
+                }
+                dev.paused = false;
+            }
+        }
+    }
+
+    /// The fd to register with epoll; becomes readable when the VT is released or reacquired.
+    pub fn vt_signal_fd(&self) -> u32 {
+        self.vt_signal_fd.0
+    }
+
+    /// Handles every pending VT release/acquire signal, pausing or resuming issued devices and
+    /// acknowledging the switch so the kernel can proceed with it.
+    pub fn process_vt_signal(&mut self) {
+        loop {
+            let mut info = mem::MaybeUninit::<linux::signalfd_siginfo>::uninit();
+            let n = linux::read(self.vt_signal_fd.0, unsafe {
+                core::slice::from_raw_parts_mut(
+                    info.as_mut_ptr() as *mut u8,
+                    mem::size_of::<linux::signalfd_siginfo>(),
+                )
+            });
+            if n == -i64::from(linux::EAGAIN) {
+                return;
+            } else if n < 0 {
+                writeln!(linux::Stderr, "failed to read VT signalfd: {n}").unwrap();
+                return;
+            }
+            let signo = unsafe { info.assume_init() }.ssi_signo;
+            if signo == linux::SIGUSR1 as u32 {
+                self.pause_all();
+                // Tell the kernel it is fine to switch the VT away now that we are paused.
+                let ret = unsafe { linux::ioctl(self.vt_fd.0, linux::VT_RELDISP, 1) };
+                if ret < 0 {
+                    writeln!(linux::Stderr, "failed to acknowledge VT release: {ret}").unwrap();
+                }
+            } else if signo == linux::SIGUSR2 as u32 {
+                let ret =
+                    unsafe { linux::ioctl(self.vt_fd.0, linux::VT_RELDISP, linux::VT_ACKACQ) };
+                if ret < 0 {
+                    writeln!(linux::Stderr, "failed to acknowledge VT acquire: {ret}").unwrap();
+                }
+                self.resume_all();
+            }
+        }
+    }
+
+    fn send_reply(&self, fd: Option<&Fd>) {
+        // We cannot send anciliary data without actual data.
+        let byte = 0u8;
+        let iov = linux::iovec {
+            iov_base: &byte as *const u8 as *mut u8,
+            iov_len: mem::size_of_val(&byte),
+        };
+        let mut rights = fd.map(|fd| RightsCtrlMsg::new(i32::try_from(fd.0).unwrap()));
+        let mut msg = linux::msghdr {
+            msg_name: ptr::null_mut(),
+            msg_namelen: 0,
+            msg_iov: &iov as *const linux::iovec as *mut linux::iovec,
+            msg_iovlen: 1,
+            msg_control: rights
+                .as_mut()
+                .map(|rights| rights as *mut RightsCtrlMsg as *mut u8)
+                .unwrap_or(ptr::null_mut()),
+            msg_controllen: rights.as_ref().map(mem::size_of_val).unwrap_or(0),
+            msg_flags: 0,
+        };
+        let ret = unsafe { linux::sendmsg(i32::try_from(self.fd.0).unwrap(), &mut msg, 0) };
+        if ret < 0 {
+            writeln!(linux::Stderr, "failed to reply to Wayland compositor: {ret}").unwrap();
+        }
+    }
+
+    /// Handles a `VERB_OPEN` request: opens `path` (a NUL-terminated device path), tracks the fd
+    /// for future pause/resume, and replies with it (or an empty datagram on error).
+    fn handle_open(&mut self, path: &[u8]) {
+        let (flags, kind) = match classify_device(path) {
+            Some(val) => val,
+            // Not a device we allow the compositor to access: report it the same way we report
+            // a failed `open`, below.
+            None => {
+                self.send_reply(None);
+                return;
+            }
+        };
+        let dev_fd = unsafe { linux::open(path.as_ptr(), flags, 0) };
+        if dev_fd < 0 {
+            self.send_reply(None);
+            return;
+        }
+        let dev_fd = Fd(u32::try_from(dev_fd).unwrap());
+        self.send_reply(Some(&dev_fd));
+
+        // Exclude the NUL terminator: `IssuedDevice::path` is compared against paths in
+        // `VERB_PAUSE`/`VERB_RESUME` requests, which also exclude it.
+        let path = &path[..path.len() - 1];
+        let slot = match self.issued.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => slot,
+            None => {
+                writeln!(
+                    linux::Stderr,
+                    "too many issued devices to track for VT switching, not tracking {:?}",
+                    path
+                )
+                .unwrap();
+                return;
+            }
+        };
+        let mut tracked_path = [0u8; 40];
+        if path.len() > tracked_path.len() {
+            writeln!(linux::Stderr, "device path too long to track for VT switching").unwrap();
+            return;
+        }
+        tracked_path[..path.len()].copy_from_slice(path);
+        *slot = Some(IssuedDevice {
+            fd: dev_fd,
+            kind,
+            paused: false,
+            path: tracked_path,
+            path_len: path.len() as u8,
+        });
+    }
+
+    /// Handles a `VERB_PAUSE`/`VERB_RESUME` request: looks up a previously issued device by path
+    /// and pauses/resumes just that one. There is no reply to these requests.
+    fn handle_pause_resume(&mut self, path: &[u8], pause: bool) {
+        let path = &path[..path.len() - 1];
+        let index = match self.find_issued(path) {
+            Some(index) => index,
+            None => return,
+        };
+        let dev = self.issued[index].as_mut().unwrap();
+        if pause {
+            if dev.paused {
+                return;
+            }
+            let (request, desc) = match dev.kind {
+                DeviceKind::Drm => (linux::DRM_IOCTL_DROP_MASTER, "drop DRM master"),
+                DeviceKind::Input => (linux::EVIOCREVOKE, "revoke input device"),
+            };
+            let ret = unsafe { linux::ioctl(dev.fd.0, request, 0) };
+            if ret < 0 {
+                writeln!(linux::Stderr, "failed to {desc}: {ret}").unwrap();
+            }
+            let kind = dev.kind;
+            dev.paused = true;
+            if kind == DeviceKind::Input {
+                // `EVIOCREVOKE` cannot be undone, so there is nothing left to track.
+                self.issued[index] = None;
+            }
+        } else if dev.paused && dev.kind == DeviceKind::Drm {
+            let ret = unsafe { linux::ioctl(dev.fd.0, linux::DRM_IOCTL_SET_MASTER, 0) };
+            if ret < 0 {
+                writeln!(linux::Stderr, "failed to set DRM master: {ret}").unwrap();
+            }
+            dev.paused = false;
+        }
     }
 
     fn process_incoming_one(&mut self) -> Result<bool, i32> {
@@ -79,66 +449,17 @@ impl SeatServer {
                 return Err(ret.try_into().unwrap());
             }
         };
-        // Datagram should be a NUL-terminated string.
-        if n == 0 || buf[n - 1] != b'\0' {
+        // Datagram should be a verb byte followed by a NUL-terminated path.
+        if n < 2 || buf[n - 1] != b'\0' {
             return Ok(true);
         }
-        let dev_fd = unsafe {
-            linux::open(
-                buf.as_ptr(),
-                linux::O_RDWR
-                    | linux::O_NOCTTY
-                    | linux::O_NOFOLLOW
-                    | linux::O_CLOEXEC
-                    | linux::O_NONBLOCK,
-                0,
-            )
-        };
-        // We cannot send anciliary data without actual data.
-        let byte = 0u8;
-        let iov = linux::iovec {
-            iov_base: &byte as *const u8 as *mut u8,
-            iov_len: mem::size_of_val(&byte),
-        };
-        if dev_fd < 0 {
-            // Send a message without an FD to the client to tell it about the error.
-            let mut msg = linux::msghdr {
-                msg_name: ptr::null_mut(),
-                msg_namelen: 0,
-                msg_iov: &iov as *const linux::iovec as *mut linux::iovec,
-                msg_iovlen: 1,
-                msg_control: ptr::null_mut(),
-                msg_controllen: 0,
-                msg_flags: 0,
-            };
-            let ret = unsafe { linux::sendmsg(i32::try_from(self.fd.0).unwrap(), &mut msg, 0) };
-            if ret < 0 {
-                writeln!(
-                    linux::Stderr,
-                    "failed to send error message to Wayland compositor: {ret}"
-                )
-                .unwrap();
-            }
-            return Ok(true);
-        }
-        let dev_fd = linux::Fd(u32::try_from(dev_fd).unwrap());
-        let mut rights = RightsCtrlMsg::new(i32::try_from(dev_fd.0).unwrap());
-        let mut msg = linux::msghdr {
-            msg_name: ptr::null_mut(),
-            msg_namelen: 0,
-            msg_iov: &iov as *const linux::iovec as *mut linux::iovec,
-            msg_iovlen: 1,
-            msg_control: &mut rights as *mut RightsCtrlMsg as *mut u8,
-            msg_controllen: mem::size_of_val(&rights),
-            msg_flags: 0,
-        };
-        let ret = unsafe { linux::sendmsg(i32::try_from(self.fd.0).unwrap(), &mut msg, 0) };
-        if ret < 0 {
-            writeln!(
-                linux::Stderr,
-                "failed to send device FD to Wayland compositor: {ret}"
-            )
-            .unwrap();
+        let verb = buf[0];
+        let path = &buf[1..n];
+        match verb {
+            VERB_OPEN => self.handle_open(path),
+            VERB_PAUSE => self.handle_pause_resume(path, true),
+            VERB_RESUME => self.handle_pause_resume(path, false),
+            _ => {}
         }
         Ok(true)
     }
@@ -1,11 +1,57 @@
 //! Powering off the system gracefully is not an easy task. This module provides
 //! routines to help.
 
-use std::{ffi::CString, fs::File, io::Read, ptr};
+use std::{ffi::CString, fs::File, io::Read, mem::MaybeUninit, ptr, time::Duration};
 
+use crate::config;
 use crate::libc_wrapper;
 
-/// Tell processes to exit and wait for them to do so.
+/// Returns the current value of `CLOCK_MONOTONIC`, or `None` if `clock_gettime` failed.
+fn monotonic_now() -> Option<Duration> {
+    let mut ts = MaybeUninit::<libc::timespec>::uninit();
+    if libc_wrapper::check_error_int(unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, ts.as_mut_ptr())
+    })
+    .is_err()
+    {
+        return None;
+    }
+    let ts = unsafe { ts.assume_init() };
+    Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// Reaps every zombie process currently waiting to be collected, without blocking.
+///
+/// Returns `true` once there is no process left to wait for (`ECHILD`).
+fn reap_available() -> bool {
+    loop {
+        let ret =
+            libc_wrapper::check_error_int(unsafe { libc::waitpid(-1, ptr::null_mut(), libc::WNOHANG) });
+        let err = match ret {
+            // `waitpid` returns 0 when called with `WNOHANG` and no child has exited yet.
+            Ok(0) => return false,
+            // A child was reaped; there may be more waiting.
+            Ok(_) => continue,
+            Err(err) => err,
+        };
+        return match err.raw_os_error() {
+            // There are no processes left.
+            Some(libc::ECHILD) => true,
+            // The function was interrupted by a signal; there may still be more to reap.
+            Some(libc::EINTR) => continue,
+            _ => {
+                eprintln!("failed to wait for processes to exit: {:?}", err);
+                // This should not happen. If it does, then we better give up now
+                // because if we don't we might be stuck in the loop with the same
+                // error over and over again.
+                true
+            }
+        };
+    }
+}
+
+/// Tell processes to exit, waiting for them to do so for up to
+/// [`config::SHUTDOWN_GRACE_PERIOD`] before forcibly killing whatever is left.
 ///
 /// Errors are printed to stderr unlike most other functions. This is because
 /// there can be multiple non critical errors that happen and will still want
@@ -25,6 +71,37 @@ pub fn end_all_processes() {
         return;
     }
 
+    // Give processes a grace period to exit on their own, reaping them as they go so that
+    // we notice once they are all gone instead of waiting out the whole grace period.
+    let deadline = monotonic_now().map(|now| now + config::SHUTDOWN_GRACE_PERIOD);
+    loop {
+        if reap_available() {
+            unsafe { libc::sync() };
+            return;
+        }
+
+        if let (Some(deadline), Some(now)) = (deadline, monotonic_now()) {
+            if now >= deadline {
+                break;
+            }
+        }
+
+        unsafe { libc::nanosleep(&libc::timespec { tv_sec: 0, tv_nsec: 50_000_000 }, ptr::null_mut()) };
+    }
+
+    // The grace period is over but some processes are still alive: escalate.
+    eprintln!("some processes did not exit in time, sending SIGKILL");
+    if let Err(err) = libc_wrapper::check_error_int(unsafe { libc::kill(-1, libc::SIGKILL) }) {
+        let no_process_found = err
+            .raw_os_error()
+            .map(|code| code == libc::ESRCH)
+            .unwrap_or(false);
+        if !no_process_found {
+            eprintln!("failed to broadcast SIGKILL: {:?}", err);
+        }
+    }
+
+    // Nothing can ignore SIGKILL, so this final reap can safely block until everyone is gone.
     loop {
         // `libc::wait` will collect the exit status of any process.
         if let Err(err) = libc_wrapper::check_error_int(unsafe { libc::wait(ptr::null_mut()) }) {
@@ -43,10 +120,34 @@ pub fn end_all_processes() {
             }
         }
     }
+
+    // Flush dirty pages to disk now that everything is dead, before the caller unmounts
+    // filesystems and powers off.
+    unsafe { libc::sync() };
+}
+
+/// Remounts the filesystem at `mountpoint` read-only in place, without touching its source
+/// device or type.
+fn remount_read_only(mountpoint: &CString) -> std::io::Result<()> {
+    libc_wrapper::check_error_int(unsafe {
+        libc::mount(
+            ptr::null(),
+            mountpoint.as_ptr(),
+            ptr::null(),
+            (libc::MS_REMOUNT | libc::MS_RDONLY) as libc::c_ulong,
+            ptr::null(),
+        )
+    })
+    .map(|_| ())
 }
 
 /// Unmounts all filesystems known to the init process.
 ///
+/// A mountpoint that is still busy (`EBUSY`) is first remounted read-only so that no more data
+/// can be lost on it, then detached lazily (`MNT_DETACH`) so that it disappears from the
+/// namespace as soon as the last reference to it is dropped. The root filesystem can never
+/// actually be unmounted, so it is always left remounted read-only as a final step.
+///
 /// Errors are printed to stderr unlike most other functions. This is because
 /// there can be multiple non critical errors that happen and will still want
 /// to continue.
@@ -83,8 +184,24 @@ pub fn unmount_all() {
             libc_wrapper::check_error_int(unsafe { libc::umount(mountpoint_cstr.as_ptr()) })
         {
             eprintln!("failed to unmount {}: {:?}", mountpoint, err);
+
+            if let Err(err) = remount_read_only(&mountpoint_cstr) {
+                eprintln!("failed to remount {} read-only: {:?}", mountpoint, err);
+            }
+
+            if let Err(err) = libc_wrapper::check_error_int(unsafe {
+                libc::umount2(mountpoint_cstr.as_ptr(), libc::MNT_DETACH)
+            }) {
+                eprintln!("failed to lazily unmount {}: {:?}", mountpoint, err);
+            }
         }
     }
+
+    // The root filesystem can never be unmounted, even lazily, because it backs the running
+    // init process itself. Make sure it is at least read-only before we power off.
+    if let Err(err) = remount_read_only(&CString::new("/").unwrap()) {
+        eprintln!("failed to remount / read-only: {:?}", err);
+    }
 }
 
 /// Actually powers off the system.
@@ -17,13 +17,13 @@ fn udev_trigger_add_action(action_type: *const u8) -> bool {
         b"add\0" as *const u8,
         ptr::null(),
     ];
-    let ret = unsafe {
-        linux::spawn_and_wait(
-            b"/bin/udevadm\0" as *const u8,
-            &argv as *const *const u8,
-            &[config::SYSTEM_PATH, ptr::null()] as *const *const u8,
-        )
+    let config = linux::ProcessConfig {
+        filename: b"/bin/udevadm\0" as *const u8,
+        argv: &argv as *const *const u8,
+        envp: &[config::SYSTEM_PATH, ptr::null()] as *const *const u8,
+        actions: &[],
     };
+    let ret = unsafe { linux::spawn_and_wait(&config) };
     match ret {
         Ok(status) if status != 0 => false,
         Err(_) => false,
@@ -34,13 +34,13 @@ fn udev_trigger_add_action(action_type: *const u8) -> bool {
 /// Starts the udev deamon, configure all devices and wait for the end of the
 /// configuration.
 fn start_udev() -> bool {
-    let ret = unsafe {
-        linux::spawn(
-            b"/lib/systemd/systemd-udevd\0" as *const u8,
-            &[b"/lib/systemd/systemd-udevd\0" as *const u8, ptr::null()] as *const *const u8,
-            &[config::SYSTEM_PATH, ptr::null()] as *const *const u8,
-        )
+    let config = linux::ProcessConfig {
+        filename: b"/lib/systemd/systemd-udevd\0" as *const u8,
+        argv: &[b"/lib/systemd/systemd-udevd\0" as *const u8, ptr::null()] as *const *const u8,
+        envp: &[config::SYSTEM_PATH, ptr::null()] as *const *const u8,
+        actions: &[],
     };
+    let ret = unsafe { linux::spawn(&config) };
     if ret < 0 {
         writeln!(linux::Stderr, "failed to start udev: {ret}").unwrap();
         return false;
@@ -63,49 +63,29 @@ fn create_xdg_runtime_dir() -> i32 {
     unsafe { linux::chown(config::XDG_RUNTIME_DIR, config::USER_UID, config::USER_GID) }
 }
 
-fn ui_process_pre_exec(_data: usize) -> bool {
-    let mut ret = linux::setgid(config::USER_GID);
-    if ret < 0 {
-        writeln!(linux::Stderr, "failed to setgid: {ret}").unwrap();
-        return false;
-    }
-    ret = linux::setgroups(config::USER_GROUPS);
-    if ret < 0 {
-        writeln!(linux::Stderr, "failed to setgroups: {ret}").unwrap();
-        return false;
-    }
-    ret = linux::setuid(config::USER_UID);
-    if ret < 0 {
-        writeln!(linux::Stderr, "failed to setuid: {ret}").unwrap();
-        return false;
-    }
-    ret = unsafe { linux::chdir(config::USER_HOME) };
-    if ret < 0 {
-        writeln!(linux::Stderr, "failed to chdir: {ret}").unwrap();
-        return false;
-    }
-    true
-}
-
-/// Starts the user interface process and returns a handle to it so that the
-/// caller can wait until it dies.
-pub fn start_ui_process() -> i32 {
+/// Starts the user interface process and returns its PID together with a pidfd that becomes
+/// readable once the process dies, so that the caller can wait for it race-free without having
+/// to catch `SIGCHLD`.
+pub fn start_ui_process() -> Result<(i32, i32), i32> {
     if !start_udev() {
-        return -linux::EINVAL;
+        return Err(-linux::EINVAL);
     }
 
     let ret = create_xdg_runtime_dir();
     if ret < 0 {
-        return ret;
+        return Err(ret);
     }
 
-    unsafe {
-        linux::spawn_with_pre_exec(
-            b"/usr/bin/sway\0" as *const u8,
-            &[b"/usr/bin/sway\0" as *const u8, ptr::null()] as *const *const u8,
-            config::SWAY_ENVP,
-            ui_process_pre_exec,
-            0,
-        )
-    }
+    let config = linux::ProcessConfig {
+        filename: b"/usr/bin/sway\0" as *const u8,
+        argv: &[b"/usr/bin/sway\0" as *const u8, ptr::null()] as *const *const u8,
+        envp: config::SWAY_ENVP,
+        actions: &[
+            linux::FileAction::SetGid(config::USER_GID),
+            linux::FileAction::SetGroups(config::USER_GROUPS),
+            linux::FileAction::SetUid(config::USER_UID),
+            linux::FileAction::Chdir(config::USER_HOME),
+        ],
+    };
+    unsafe { linux::spawn_pidfd(&config) }
 }